@@ -1,12 +1,19 @@
-use crate::chapter::{ Chapter, ChapterError, ImageDownloadError, ChapterMetadata, ChapterMetadataSeries };
-use crate::coverart::CoverArt;
+use crate::ContentRating;
+use crate::chapter::{ Chapter, ChapterError, DownloadedChapter, ImageDownloadError, ChapterMetadata, ChapterMetadataSeries };
+use crate::coverart::{ CoverArt, DownloadedCover };
+use crate::image::{ Image, PartFile };
+use crate::layout::Layout;
 use crate::manga::MangaMetadata;
-use crate::requester::{ RateLimitedRequester, RequesterError };
-use crate::types::{ ChapterDataResponse, CoverArtResponse, MangaDataResponse };
+use crate::process::Processor;
+use crate::requester::{ RateLimitedRequester, RequesterError, DEFAULT_API_URL, DEFAULT_UPLOADS_URL };
+use crate::types::{ ChapterData, ChapterDataResponse, CoverArtResponse, Include, MangaDataResponse };
 use crate::utils;
 
+use std::collections::HashSet;
 use std::path::Path;
 
+use chrono::{ DateTime, Utc };
+use log::info;
 use pbr::ProgressBar;
 use thiserror::Error;
 
@@ -22,61 +29,176 @@ pub enum APIError {
     Chapter(#[from] ChapterError),
     #[error("error downloading images: {0}")]
     Download(#[from] ImageDownloadError),
+    #[error("chapter feed could not be paged past the API's offset limit, as too many chapters were created in the same second")]
+    FeedWindow,
 }
 
+// Largest page size the chapter feed accepts
+const FEED_LIMIT:u64 = 500;
+// The chapter feed rejects requests where offset + limit exceeds this
+const FEED_WINDOW:u64 = 10000;
+
+/// Filters applied server-side to the chapter feed, so that chapters that would be thrown away anyway aren't fetched.
+#[derive(Debug, Clone, Default)]
+pub struct FeedFilter {
+    pub languages: Vec<String>,
+    pub excluded_groups: Vec<String>,
+    pub content_ratings: Vec<ContentRating>,
+}
+impl FeedFilter {
+    fn to_query(&self) -> String {
+        let encode = |value:&str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+
+        let languages = self.languages.iter()
+            .map(|l| format!("&translatedLanguage[]={}", encode(l)));
+
+        let excluded_groups = self.excluded_groups.iter()
+            .map(|g| format!("&excludedGroups[]={}", encode(g)));
+
+        let content_ratings = self.content_ratings.iter()
+            .map(|cr| format!("&contentRating[]={}", encode(cr.as_str())));
+
+        languages.chain(excluded_groups)
+            .chain(content_ratings)
+            .collect::<String>()
+    }
+}
+
+/// Builds an [`API`] client, e.g. `API::builder().api_url("http://localhost:8080").build()`.
+#[derive(Debug, Clone)]
+pub struct APIBuilder {
+    api_url: String,
+    uploads_url: String,
+}
+impl Default for APIBuilder {
+    fn default() -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            uploads_url: DEFAULT_UPLOADS_URL.to_string(),
+        }
+    }
+}
+impl APIBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Base URL of the MangaDex API. Defaults to https://api.mangadex.org.
+    pub fn api_url(mut self, api_url:&str) -> Self {
+        self.api_url = api_url.to_string();
+        self
+    }
+
+    /// Base URL cover art is served from. Defaults to https://uploads.mangadex.org.
+    pub fn uploads_url(mut self, uploads_url:&str) -> Self {
+        self.uploads_url = uploads_url.to_string();
+        self
+    }
+
+    pub fn build(self) -> Result<API, APIError> {
+        API::new_with_base_urls(&self.api_url, &self.uploads_url)
+    }
+}
+
+/// Rate limited MangaDex client.
 pub struct API {
     requester: RateLimitedRequester,
 }
 impl API {
-    pub fn new() -> Self {
-        Self {
-            requester: RateLimitedRequester::new_with_defaults(),
-        }
+    pub fn builder() -> APIBuilder {
+        APIBuilder::new()
     }
 
+    pub fn new_with_base_urls(api_url:&str, uploads_url:&str) -> Result<Self, APIError> {
+        Ok(Self {
+            requester: RateLimitedRequester::new_with_base_urls(api_url, uploads_url)?,
+        })
+    }
+
+    /// Retrieves a manga's metadata, given its URL on mangadex.org.
     pub async fn get_manga_metadata(&mut self, url:&str) -> Result<MangaMetadata, APIError> {
         let id = utils::get_id(url).ok_or(APIError::NoID)?;
-        let raw_manga_data:MangaDataResponse = self.requester.request_json("main", &format!("/manga/{}", id))
+        let includes = Include::to_query(&[Include::Author, Include::Artist, Include::CoverArt]);
+        let raw_manga_data:MangaDataResponse = self.requester.request_json("main", &format!("/manga/{}?{}", id, &includes[1..]))
             .await?;
 
         Ok(MangaMetadata::from_response(id, raw_manga_data))
     }
 
-    pub async fn get_chapter_metadata(&mut self, manga_metadata:&MangaMetadata, quiet:bool) -> Result<ChapterMetadataSeries, APIError> {
-        let res:ChapterDataResponse = self.requester
-            .request_json("main", &format!("/manga/{}/feed?offset={}&includes[]=scanlation_group", &manga_metadata.id, 0))
-            .await?;
+    /// Retrieves the metadata of every chapter in the manga's feed that passes the filter.
+    pub async fn get_chapter_metadata(&mut self, manga_metadata:&MangaMetadata, filter:&FeedFilter, quiet:bool) -> Result<ChapterMetadataSeries, APIError> {
+        let mut chapters = ChapterMetadataSeries::new(Vec::new());
+        let mut seen = HashSet::new();
+        let mut pb:Option<ProgressBar<std::io::Stdout>> = None;
 
-        let mut chapters = ChapterMetadataSeries::new(res.data);
-        let total = res.total;
-        let mut i = res.offset + res.limit;
+        // The API refuses to page past offset + limit > 10000. Past that, restart paging from the creation date of the last
+        // chapter seen, which works as long as the feed is ordered by creation date. Creation dates only go down to the
+        // second and bulk uploads share them, so the new window skips the chapters from that second that were already seen.
+        let query = filter.to_query();
+        let includes = Include::to_query(&[Include::ScanlationGroup]);
+        let mut since:Option<String> = None;
+        let mut offset = 0;
+        let mut last_second:Option<String> = None;
+        let mut same_second = 0;
+        loop {
+            let limit = FEED_LIMIT.min(FEED_WINDOW - offset);
+            let mut path = format!("/manga/{}/feed?limit={}&offset={}&order[createdAt]=asc{}{}", manga_metadata.id, limit, offset, includes, query);
+            if let Some(since) = &since {
+                path.push_str(&format!("&createdAtSince={}", since));
+            }
 
-        let mut pb = match quiet {
-            false => Some(ProgressBar::new(total)),
-            true => None,
-        };
+            let res:ChapterDataResponse = self.requester.request_json("main", &path).await?;
 
-        while i < total {
-            let res:ChapterDataResponse = self.requester
-                .request_json("main", &format!("/manga/{}/feed?offset={}&includes[]=scanlation_group", &manga_metadata.id, i))
-                .await?;
+            if pb.is_none() && !quiet {
+                pb = Some(ProgressBar::new(res.total));
+            }
+
+            // Windows shouldn't overlap, but don't count on the order of chapters created in the same second being stable
+            let n = res.data.len() as u64;
+            let data = res.data.into_iter()
+                .filter(|c| seen.insert(c.id.clone()))
+                .collect::<Vec<ChapterData>>();
+
+            for second in data.iter().map(created_second) {
+                if second.is_some() && second == last_second {
+                    same_second += 1;
+                } else {
+                    last_second = second;
+                    same_second = 1;
+                }
+            }
 
             if let Some(pb) = &mut pb {
-                pb.add(res.data.len() as u64);
+                pb.add(data.len() as u64);
             }
 
-            chapters.add_metadata(res.data);
-            i += res.limit;
+            chapters.add_metadata(data);
+
+            offset += res.limit;
+            if n < res.limit || offset >= res.total {
+                break;
+            }
+
+            if offset >= FEED_WINDOW {
+                // Only stuck if a whole window's worth of chapters were created in the same second
+                if last_second.is_none() || same_second >= FEED_WINDOW {
+                    return Err(APIError::FeedWindow);
+                }
+
+                since = last_second.clone();
+                offset = same_second;
+            }
         }
 
         if let Some(pb) = &mut pb {
-            pb.finish_print("Chapter metadata downloaded.");
-            println!("");
+            pb.finish();
         }
+        info!("Chapter metadata downloaded.");
 
         Ok(chapters)
     }
 
+    /// Retrieves where each chapter's pages can be downloaded from.
     pub async fn get_chapters(&mut self, chapter_metadata:&[&ChapterMetadata], quiet:bool) -> Result<Vec<Chapter>, APIError> {
         let mut pb = match quiet {
             false => Some(ProgressBar::new(chapter_metadata.len() as u64)),
@@ -84,9 +206,8 @@ impl API {
         };
 
         let mut chapters = vec![];
-        let mut iter = chapter_metadata.iter();
-        while let Some(metadata) = iter.next() {
-            let chapter = Chapter::new(&mut self.requester, &metadata).await?;
+        for metadata in chapter_metadata.iter() {
+            let chapter = Chapter::new(&mut self.requester, metadata).await?;
             chapters.push(chapter);
 
             if let Some(pb) = &mut pb {
@@ -95,33 +216,31 @@ impl API {
         }
 
         if let Some(pb) = &mut pb {
-            pb.finish_print("Chapter download data downloaded.");
-            println!("");
+            pb.finish();
         }
+        info!("Chapter download data downloaded.");
 
         Ok(chapters)
     }
 
-    pub async fn download_chapters(&mut self, chapters:&[Chapter], master_directory:&Path, quiet:bool) -> Result<(), APIError> {
-        let mut iter = chapters.iter();
-
-        let mut pb:Option<ProgressBar<std::io::Stdout>> = None;
-        while let Some(chapter) = iter.next() {
-            if let Some(pb) = &mut pb {
-                pb.finish();
-            }
+    /// Downloads a single page of a chapter to a hidden file in `directory`, checking it against its hash.
+    pub async fn download_page(&mut self, chapter:&Chapter, image:&Image, directory:&Path) -> Result<PartFile, APIError> {
+        Ok(chapter.download_page(&mut self.requester, image, directory).await?)
+    }
 
-            pb = chapter.download_to_folder(&mut self.requester, master_directory, quiet).await?;
+    /// Downloads the pages of each chapter into its own folder under `master_directory`.
+    pub async fn download_chapters(&mut self, chapters:&[Chapter], master_directory:&Path, layout:&Layout, processor:Option<&Processor>, verify:bool, quiet:bool) -> Result<Vec<DownloadedChapter>, APIError> {
+        let mut downloaded = Vec::with_capacity(chapters.len());
+        for chapter in chapters.iter() {
+            downloaded.push(chapter.download_to_folder(&mut self.requester, master_directory, layout, processor, verify, quiet).await?);
         }
 
-        if let Some(pb) = &mut pb {
-            pb.finish_print("Chapters downloaded");
-            println!("");
-        }
+        info!("Chapters downloaded.");
 
-        Ok(())
+        Ok(downloaded)
     }
 
+    /// Retrieves the metadata of every cover of the manga with the given ID.
     pub async fn get_cover_art(&mut self, id:&str, quiet:bool) -> Result<Vec<CoverArt>, APIError> {
         let res = self.requester.request("main", &format!("/cover?manga[]={}&offset={}", id, 0))
             .await?
@@ -152,22 +271,28 @@ impl API {
         }
 
         if let Some(pb) = &mut pb {
-            pb.finish_print("Cover art metadata downloaded.");
-            println!("");
+            pb.finish();
         }
+        info!("Cover art metadata downloaded.");
 
         Ok(covers)
     }
 
-    pub async fn download_cover_art(&mut self, cover_art:&[CoverArt], master_directory:&Path, quiet:bool) -> Result<(), APIError> {
+    /// Downloads the manga's main cover into `master_directory`, as cover.<ext>.
+    pub async fn download_series_cover(&mut self, cover_art:&CoverArt, master_directory:&Path) -> Result<DownloadedCover, APIError> {
+        Ok(cover_art.download_to(&mut self.requester, master_directory).await?)
+    }
+
+    /// Downloads each cover to wherever the layout puts volume covers under `master_directory`.
+    pub async fn download_cover_art(&mut self, cover_art:&[CoverArt], master_directory:&Path, layout:&Layout, quiet:bool) -> Result<Vec<DownloadedCover>, APIError> {
         let mut pb = match quiet {
             false => Some(ProgressBar::new(cover_art.len() as u64)),
             true => None,
         };
 
-        let mut iter = cover_art.iter();
-        while let Some(ca) = iter.next() {
-            ca.download(&mut self.requester, master_directory).await?;
+        let mut downloaded = Vec::with_capacity(cover_art.len());
+        for ca in cover_art.iter() {
+            downloaded.push(ca.download(&mut self.requester, master_directory, layout).await?);
 
             if let Some(pb) = &mut pb {
                 pb.inc();
@@ -175,10 +300,16 @@ impl API {
         }
 
         if let Some(pb) = &mut pb {
-            pb.finish_print("Cover art downloaded.");
-            println!("");
+            pb.finish();
         }
+        info!("Cover art downloaded.");
 
-        Ok(())
+        Ok(downloaded)
     }
 }
+
+// Creation date of a chapter to the second, as `createdAtSince` takes it
+fn created_second(chapter:&ChapterData) -> Option<String> {
+    let created_at = DateTime::parse_from_rfc3339(chapter.attributes.created_at.as_ref()?).ok()?;
+    Some(created_at.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%S").to_string())
+}
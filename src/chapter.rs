@@ -1,25 +1,40 @@
-use crate::image::Image;
-use crate::range::Range;
+use crate::TLSelectionStrategy;
+use crate::image::{ self, Image, PartFile };
+use crate::layout::Layout;
+use crate::process::{ ProcessError, Processor };
+use crate::range::RangeSet;
 use crate::requester::{ RateLimitedRequester, RequesterError };
-use crate::types::{ ChapterData, ChapterImageResponse };
+use crate::sanitise::Sanitiser;
 use crate::utils;
+use crate::types::{ ChapterData, ChapterImageResponse, Relationship };
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
 use std::fs::{ self, File };
 use std::io::Write;
 use std::time::Duration;
 
+use chrono::DateTime;
 use pbr::ProgressBar;
+use serde::{ Deserialize, Serialize };
 use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChapterMetadata {
     pub id:String,
     pub volume: String,
     pub chapter: String,
+    pub title: String,
     pub language: String,
     pub group: String,
+    pub group_ids: Vec<String>,
+    pub uploader: String,
+    pub pages: u64,
+    pub version: u64,
+    pub publish_at: String,
+    pub readable_at: String,
+    pub external_url: Option<String>,
 }
 impl ChapterMetadata {
     pub fn from_chapter_data(raw:ChapterData) -> Option<Self> {
@@ -34,18 +49,143 @@ impl ChapterMetadata {
         };
 
         let group = raw.relationships.iter()
-            .filter(|r| r.id == "scanlation_group")
-            .filter_map(|r| Some(r.attributes.as_ref()?.name.as_ref()?.clone()))
-            .next().unwrap_or(String::new());
+            .find_map(|r| match r {
+                Relationship::ScanlationGroup { attributes: Some(attributes), .. } => attributes.name.clone(),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let group_ids = raw.relationships.iter()
+            .filter_map(|r| match r {
+                Relationship::ScanlationGroup { id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let uploader = raw.relationships.iter()
+            .find_map(|r| match r {
+                Relationship::User { id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
 
         Some(Self {
             id: raw.id,
             volume,
             chapter,
+            title: raw.attributes.title.unwrap_or_default(),
             language: raw.attributes.language?,
             group,
+            group_ids,
+            uploader,
+            pages: raw.attributes.pages,
+            version: raw.attributes.version,
+            publish_at: raw.attributes.publish_at.unwrap_or_default(),
+            readable_at: raw.attributes.readable_at.unwrap_or_default(),
+            external_url: raw.attributes.external_url,
         })
     }
+
+    // Numbers are zero padded to width, e.g. Volume 01
+    pub fn get_volume(&self, width:usize) -> String {
+        match self.volume.parse::<f64>() {
+            Ok(v) => format!("Volume {}", utils::pad_number(v, width)),
+            Err(_) => self.volume.clone(),
+        }
+    }
+
+    pub fn get_chapter(&self, width:usize) -> String {
+        match self.chapter.parse::<f64>() {
+            Ok(c) => format!("Chapter {}", utils::pad_number(c, width)),
+            Err(_) => self.chapter.clone(),
+        }
+    }
+
+    pub fn get_publish_date(&self) -> String {
+        match DateTime::parse_from_rfc3339(&self.publish_at) {
+            Ok(date) => date.format("%Y-%m-%d").to_string(),
+            Err(_) => self.publish_at.clone(),
+        }
+    }
+
+    pub fn assemble_folder_name(&self, chapter_titles:bool, volume_width:usize, chapter_width:usize, sanitiser:&Sanitiser) -> String {
+        let v = self.get_volume(volume_width);
+        let c = match (chapter_titles, self.title.is_empty()) {
+            (true, false) => format!("{} - {}", self.get_chapter(chapter_width), self.title),
+            _ => self.get_chapter(chapter_width),
+        };
+
+        match (v.is_empty(), c.is_empty()) {
+            (false, false) => format!("{}/{}", sanitiser.sanitise(&v), sanitiser.sanitise(&c)),
+            (true, false) => sanitiser.sanitise(&c),
+            (false, true) => sanitiser.sanitise(&v),
+            (true, true) => String::from("Oneshot"),
+        }
+    }
+
+    pub fn is_external(&self) -> bool {
+        self.external_url.is_some()
+    }
+
+    // Writes an internet shortcut in place of the chapter folder, pointing to wherever the chapter is actually hosted
+    pub fn write_shortcut(&self, master_directory:&Path, layout:&Layout) -> Result<(), std::io::Error> {
+        let url = match &self.external_url {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+
+        let path = master_directory.join(Path::new(&format!("{}.url", layout.chapter_path(self))));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(format!("[InternetShortcut]\r\nURL={}\r\n", url).as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn print_table(chapters:&[&ChapterMetadata], master_directory:&Path, layout:&Layout) {
+        let header = ["Volume", "Chapter", "Title", "Group", "Language", "Pages", "Published", "External", "Path"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+
+        let rows = chapters.iter()
+            .map(|m| vec![
+                m.volume.clone(),
+                m.chapter.clone(),
+                m.title.clone(),
+                m.group.clone(),
+                m.language.clone(),
+                m.pages.to_string(),
+                m.get_publish_date(),
+                match m.external_url { Some(_) => String::from("yes"), None => String::new() },
+                master_directory.join(Path::new(&layout.chapter_file(m))).display().to_string(),
+            ])
+            .collect::<Vec<Vec<String>>>();
+
+        let widths = rows.iter()
+            .chain(std::iter::once(&header))
+            .fold(vec![0; header.len()], |mut acc, row| {
+                for (width, cell) in acc.iter_mut().zip(row.iter()) {
+                    *width = (*width).max(cell.chars().count());
+                }
+
+                acc
+            });
+
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            let line = row.iter().zip(widths.iter())
+                .map(|(cell, width)| format!("{:width$}", cell, width=width))
+                .collect::<Vec<String>>()
+                .join("  ");
+
+            println!("{}", line.trim_end());
+        }
+
+        println!("{} chapter(s)", rows.len());
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -72,7 +212,7 @@ impl ChapterMetadataSeries {
     pub fn add_metadata(&mut self, mut raw:Vec<ChapterData>) {
         // Convert new chapters
         let mut chapters = raw.drain(..)
-            .filter_map(|r| ChapterMetadata::from_chapter_data(r))
+            .filter_map(ChapterMetadata::from_chapter_data)
             .collect::<Vec<ChapterMetadata>>();
 
         // Recalculate TL-groups
@@ -95,35 +235,205 @@ impl ChapterMetadataSeries {
         self.chapters.append(&mut chapters);
     }
 
-    pub fn get_download_metadata(&self, language:&str, preferred_tl:&Option<String>, ranges:&Option<Vec<Range>>) -> Vec<&ChapterMetadata> {
-        // Initially filter for language and range
+    // Fills in missing volumes from other chapters with the same number, or failing that, from the chapters either side
+    // of it if they agree. Returns the number of chapters that had their volume inferred.
+    pub fn infer_volumes(&mut self) -> usize {
+        let mut known = self.chapters.iter()
+            .filter(|m| !m.volume.is_empty())
+            .filter_map(|m| Some((m.chapter.parse::<f64>().ok()?, m.volume.clone())))
+            .collect::<Vec<(f64, String)>>();
+        known.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut inferred = 0;
+        for m in self.chapters.iter_mut().filter(|m| m.volume.is_empty()) {
+            let c = match m.chapter.parse::<f64>() {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            let i = known.partition_point(|(k, _)| *k < c);
+            let volume = match known.get(i) {
+                Some((k, v)) if *k == c => Some(v),
+                next => match (i.checked_sub(1).and_then(|j| known.get(j)), next) {
+                    (Some((_, a)), Some((_, b))) if a == b => Some(a),
+                    _ => None,
+                },
+            };
+
+            if let Some(volume) = volume {
+                m.volume = volume.clone();
+                inferred += 1;
+            }
+        }
+
+        inferred
+    }
+
+    // Highest volume and chapter numbers in the series, e.g. for working out how far to pad numbers
+    pub fn get_max_numbers(&self) -> (Option<f64>, Option<f64>) {
+        let max = |numbers:Vec<f64>| numbers.into_iter().reduce(f64::max);
+        let volumes = self.chapters.iter().filter_map(|c| c.volume.parse::<f64>().ok()).collect::<Vec<f64>>();
+        let chapters = self.chapters.iter().filter_map(|c| c.chapter.parse::<f64>().ok()).collect::<Vec<f64>>();
+
+        (max(volumes), max(chapters))
+    }
+
+    pub fn get_download_metadata(&self, language:&str, preferred_tl:&Option<String>, ranges:&Option<RangeSet>, strategy:TLSelectionStrategy) -> Vec<&ChapterMetadata> {
+        // Initially filter for language
         let chapters:HashMap<(&String, &String), Vec<&ChapterMetadata>> = self.chapters.iter()
             .filter(|m| m.language == language)
-            .filter(|m| ranges.as_ref().map(|r| r.iter().any(|range| range.in_range(&m.volume, &m.chapter))).unwrap_or(true))
             .fold(HashMap::new(), |mut acc, m| {
                 if let Some(ms) = acc.get_mut(&(&m.volume, &m.chapter)) {
-                    ms.push(&m);
+                    ms.push(m);
                 } else {
-                    acc.insert((&m.volume, &m.chapter), vec![&m]);
+                    acc.insert((&m.volume, &m.chapter), vec![m]);
                 }
 
                 acc
             });
 
+        // Order chapters so that neighbouring chapters are adjacent
+        let mut chapters = chapters.into_values().collect::<Vec<Vec<&ChapterMetadata>>>();
+        chapters.sort_by(|a, b| compare_chapters(a[0], b[0])); // Guaranteed != 0
+
+        // Filter for range. Recency is needed for 'latest' ranges.
+        let recency = recency_ranks(&chapters);
+        let chapters = chapters.into_iter()
+            .zip(recency)
+            .filter(|(ms, recency)| ranges.as_ref().map(|r| r.in_range(&ms[0].volume, &ms[0].chapter, *recency)).unwrap_or(true))
+            .map(|(ms, _)| ms)
+            .collect::<Vec<Vec<&ChapterMetadata>>>();
+
+        let preferred_tl = preferred_tl.as_ref().unwrap_or(&self.max_tl);
+
         // Filter for TL group
-        chapters.iter()
-            .map(|(_, ms)| match ms.len() { 
-                1 => ms[0],
-                _ => ms.iter() // Guaranteed != 0
-                        .filter(|m| match preferred_tl {
-                            Some(tl) => &m.group == tl,
-                            None => m.group == self.max_tl,
+        match strategy {
+            TLSelectionStrategy::Frequency => chapters.iter()
+                .map(|ms| match ms.len() {
+                    1 => ms[0],
+                    _ => ms.iter() // Guaranteed != 0
+                            .find(|m| &m.group == preferred_tl)
+                            .unwrap_or(&ms[0]),
+                })
+                .collect::<Vec<&ChapterMetadata>>(),
+            TLSelectionStrategy::Consistent => select_consistent(&chapters, preferred_tl),
+        }
+    }
+
+    // Pairs of consecutive chapters where the TL-group changes because the previous group never uploaded the next chapter
+    pub fn get_forced_switches<'a>(&self, chapters:&[&'a ChapterMetadata]) -> Vec<(&'a ChapterMetadata, &'a ChapterMetadata)> {
+        let uploaded_by = |group:&str, to:&ChapterMetadata| self.chapters.iter()
+            .any(|m| m.group == group && m.language == to.language && m.volume == to.volume && m.chapter == to.chapter);
+
+        chapters.windows(2)
+            .filter(|w| w[0].group != w[1].group && !uploaded_by(&w[0].group, w[1]))
+            .map(|w| (w[0], w[1]))
+            .collect()
+    }
+}
+
+// Chapters without a volume are generally the latest ones, so they go last
+fn compare_chapters(a:&ChapterMetadata, b:&ChapterMetadata) -> Ordering {
+    let key = |m:&ChapterMetadata| (
+        m.volume.parse::<f64>().unwrap_or(f64::INFINITY),
+        m.chapter.parse::<f64>().unwrap_or(f64::INFINITY),
+    );
+
+    let (av, ac) = key(a);
+    let (bv, bc) = key(b);
+    av.total_cmp(&bv).then(ac.total_cmp(&bc))
+}
+
+// How recent each chapter is, 0 being the latest. This goes by chapter number rather than reading order, which puts
+// chapters without a volume last whatever their number. Chapters without a number, e.g. extras, count as coming just after
+// the last numbered chapter published before them.
+fn recency_ranks(chapters:&[Vec<&ChapterMetadata>]) -> Vec<usize> {
+    let published = |ms:&[&ChapterMetadata]| ms.iter().map(|m| m.publish_at.clone()).min().unwrap_or_default();
+    let numbered = chapters.iter()
+        .filter_map(|ms| Some((ms[0].chapter.parse::<f64>().ok()?, published(ms))))
+        .collect::<Vec<(f64, String)>>();
+
+    let keys = chapters.iter()
+        .map(|ms| {
+            let published = published(ms);
+            let number = ms[0].chapter.parse::<f64>().unwrap_or_else(|_| numbered.iter()
+                .filter(|(_, p)| p <= &published)
+                .map(|(n, _)| *n)
+                .fold(f64::NEG_INFINITY, f64::max));
+
+            (number, published)
+        })
+        .collect::<Vec<(f64, String)>>();
+
+    // Latest first
+    let mut order = (0..chapters.len()).collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| keys[b].0.total_cmp(&keys[a].0).then_with(|| keys[b].1.cmp(&keys[a].1)));
+
+    let mut ranks = vec![0; chapters.len()];
+    for (rank, i) in order.into_iter().enumerate() {
+        ranks[i] = rank;
+    }
+
+    ranks
+}
+
+// Pick one upload per chapter such that the number of TL-group switches over the whole (ordered) run is minimal.
+// Ties are broken in favour of the preferred group. Classic DP over chapters, cost is (switches, non-preferred picks).
+fn select_consistent<'a>(chapters:&[Vec<&'a ChapterMetadata>], preferred_tl:&str) -> Vec<&'a ChapterMetadata> {
+    let candidates = chapters.iter()
+        .map(group_candidates)
+        .collect::<Vec<Vec<&ChapterMetadata>>>();
+
+    // costs[i][j] is the best cost of a run ending with candidate j of chapter i, along with the candidate it came from
+    let mut costs:Vec<Vec<((u64, u64), usize)>> = Vec::with_capacity(candidates.len());
+    for (i, cs) in candidates.iter().enumerate() {
+        let row = cs.iter()
+            .map(|m| {
+                let penalty = (m.group != preferred_tl) as u64;
+                match i {
+                    0 => ((0, penalty), 0),
+                    _ => costs[i - 1].iter().enumerate()
+                        .map(|(j, ((switches, penalties), _))| {
+                            let switched = (candidates[i - 1][j].group != m.group) as u64;
+                            ((switches + switched, penalties + penalty), j)
                         })
-                        .next()
-                        .unwrap_or(&ms[0]),
+                        .min_by_key(|(cost, _)| *cost)
+                        .unwrap(), // Guaranteed != 0
+                }
             })
-            .collect::<Vec<&ChapterMetadata>>()
+            .collect::<Vec<((u64, u64), usize)>>();
+
+        costs.push(row);
     }
+
+    // Walk back from the cheapest ending
+    let mut selected = Vec::with_capacity(candidates.len());
+    let mut j = match costs.last() {
+        Some(row) => row.iter().enumerate()
+            .min_by_key(|(_, (cost, _))| *cost)
+            .map(|(j, _)| j)
+            .unwrap(), // Guaranteed != 0
+        None => return selected,
+    };
+
+    for i in (0..candidates.len()).rev() {
+        selected.push(candidates[i][j]);
+        j = costs[i][j].1;
+    }
+
+    selected.reverse();
+    selected
+}
+
+// Only one candidate per group is needed, so later uploads by a group that already has one are dropped
+fn group_candidates<'a, 'b>(uploads:impl IntoIterator<Item=&'b &'a ChapterMetadata>) -> Vec<&'a ChapterMetadata> where 'a: 'b {
+    uploads.into_iter().fold(Vec::new(), |mut acc:Vec<&ChapterMetadata>, m| {
+        if !acc.iter().any(|c| c.group == m.group) {
+            acc.push(m);
+        }
+
+        acc
+    })
 }
 
 #[derive(Debug, Error)]
@@ -152,13 +462,29 @@ pub enum ImageDownloadError {
     IO(#[from] std::io::Error),
     #[error("downloaded image has different hash to supplied one")]
     HashMismatch,
+    #[error("error packing chapter into cbz: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    #[error("error processing image: {0}")]
+    Process(#[from] ProcessError),
+}
+
+/// A chapter that has been written to disk.
+#[derive(Debug, Clone)]
+pub struct DownloadedChapter {
+    pub metadata: ChapterMetadata,
+    /// Folder the chapter's pages were saved to
+    pub directory: PathBuf,
+    /// Saved pages, in reading order
+    pub pages: Vec<PathBuf>,
+    /// CBZ the pages were packed into, if any. The folder is removed once packed; pages keep their paths relative to the folder inside it.
+    pub archive: Option<PathBuf>,
+    /// The MangaDex page each saved page is, if they were saved unchanged, i.e. not processed
+    pub sources: Option<Vec<Image>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Chapter {
-    pub id:String,
-    pub volume: String,
-    pub chapter: String,
+    pub metadata: ChapterMetadata,
     pub base_url: String,
     pub urls: Vec<Image>,
 }
@@ -174,84 +500,266 @@ impl Chapter {
             .collect::<Result<Vec<Image>, ChapterError>>()?;
 
         Ok(Self {
-            id: metadata.id.clone(),
-            volume: metadata.volume.clone(),
-            chapter: metadata.chapter.clone(),
+            metadata: metadata.clone(),
             base_url: res.base_url,
             urls,
         })
     }
 
-    pub fn get_volume(&self) -> String {
-        match self.volume.parse::<f64>() {
-            Ok(v) => format!("Volume {}", v),
-            Err(_) => self.volume.clone(),
-        }
-    }
-
-    pub fn get_chapter(&self) -> String {
-        match self.chapter.parse::<f64>() {
-            Ok(c) => format!("Chapter {}", c),
-            Err(_) => self.chapter.clone(),
-        }
-    }
-
-    fn assemble_folder_name(&self) -> String {
-        let v = self.get_volume();
-        let c = self.get_chapter();
-
-        match (v.is_empty(), c.is_empty()) {
-            (false, false) => format!("{}/{}", utils::escape_path(&v), utils::escape_path(&c)),
-            (true, false) => utils::escape_path(&c),
-            (false, true) => utils::escape_path(&v),
-            (true, true) => String::from("Oneshot"),
+    pub async fn download_to_folder(&self, requester:&mut RateLimitedRequester, master_directory:&Path, layout:&Layout, processor:Option<&Processor>, verify:bool, quiet:bool) -> Result<DownloadedChapter, ImageDownloadError> {
+        let master_path = master_directory.join(Path::new(&layout.chapter_path(&self.metadata)));
+        let mut pages = Vec::with_capacity(self.urls.len());
+        let mut processing = Vec::new();
+
+        // Nothing to download, so don't leave an empty folder behind
+        if self.urls.is_empty() {
+            return Ok(DownloadedChapter {
+                metadata: self.metadata.clone(),
+                directory: master_path,
+                pages,
+                archive: None,
+                sources: Some(Vec::new()),
+            });
         }
-    }
 
-    pub async fn download_to_folder(&self, requester:&mut RateLimitedRequester, master_directory:&Path, quiet:bool) -> Result<Option<ProgressBar<std::io::Stdout>>, ImageDownloadError> {
         let _ = requester.insert_source(&self.base_url, &self.base_url, Duration::from_millis(100)); // Ignore conflicting aliases
-        let master_path = master_directory.join(Path::new(&self.assemble_folder_name()));
         fs::create_dir_all(&master_path)?;
+        PartFile::clear_stale(&master_path).await?;
 
         let mut pb = match quiet {
             false => Some(ProgressBar::new(self.urls.len() as u64)),
             true => None,
         };
 
-        let digits = (self.urls.len() as f64).log10().floor() as usize + 1;
-
         for (i, image) in self.urls.iter().enumerate() {
-            let res = requester.request(&self.base_url, image.url()).await?;
+            let (part, content_type) = self.fetch_page(requester, image, &master_path, verify).await?;
+
+            let extension = match image::extension(&part.head, content_type.as_deref(), &format!("Page {} of {}", i + 1, self.metadata.id)) {
+                Ok(extension) => extension,
+                Err(e) => {
+                    part.discard().await;
+                    return Err(e);
+                },
+            };
 
-            // Derive extension from response Content-Type
-            let content_type = res.headers().get("Content-Type")
-                .ok_or(ImageDownloadError::NoContentType)?
-                .to_str()?;
+            let path = master_path.join(Path::new(&layout.page_path(&self.metadata, i + 1, self.urls.len(), &extension)));
+            part.persist(&path).await?;
 
-            let extension = mime_guess::get_mime_extensions_str(content_type)
-                .ok_or(ImageDownloadError::Mime)?
-                .iter().map(|s| *s)
-                .next()
-                .ok_or(ImageDownloadError::Mime)?;
+            // Pages are processed while the rest download, unless they're to be stitched together first
+            match processor {
+                Some(processor) if !processor.stitches() => processing.push(processor.spawn(path)),
+                _ => pages.push(path),
+            }
 
-            // Get the body
-            let body = res.bytes().await?;
+            if let Some(pb) = &mut pb {
+                pb.inc();
+            }
+        }
+
+        if let Some(pb) = &mut pb {
+            pb.finish();
+        }
 
-            // Verify the body. TODO: make this optional.
-            if image.verify(&body) == false {
-                return Err(ImageDownloadError::HashMismatch);
+        if let Some(processor) = processor.filter(|p| p.stitches()) {
+            processing.push(processor.stitch(std::mem::take(&mut pages)));
+        }
+
+        if !processing.is_empty() {
+            let mut processed = Vec::new();
+            for handle in processing {
+                processed.extend(handle.await.map_err(ProcessError::from)??);
             }
 
-            // I'm too lazy to do async file io
-            let path = master_path.join(Path::new(&format!("{:0digits$}.{}", i + 1, extension, digits=digits)));
-            let mut file = File::create(path)?;
-            let _ = file.write_all(&body)?;
+            pages = self.renumber(&master_path, layout, processed)?;
+        }
 
-            if let Some(pb) = &mut pb {
-                pb.inc();
+        let archive = match layout.archive() {
+            true => Some(Self::pack(&master_path, &pages)?),
+            false => None,
+        };
+
+        Ok(DownloadedChapter {
+            metadata: self.metadata.clone(),
+            directory: master_path,
+            pages,
+            archive,
+            sources: processor.is_none().then(|| self.urls.clone()),
+        })
+    }
+
+    /// Downloads a single page to a hidden file in `directory`, and checks it against its hash.
+    pub async fn download_page(&self, requester:&mut RateLimitedRequester, image:&Image, directory:&Path) -> Result<PartFile, ImageDownloadError> {
+        let _ = requester.insert_source(&self.base_url, &self.base_url, Duration::from_millis(100));
+        self.fetch_page(requester, image, directory, true).await.map(|(part, _)| part)
+    }
+
+    // Also returns the Content-Type the page was served as
+    async fn fetch_page(&self, requester:&mut RateLimitedRequester, image:&Image, directory:&Path, verify:bool) -> Result<(PartFile, Option<String>), ImageDownloadError> {
+        let res = requester.request(&self.base_url, image.url()).await?;
+
+        let content_type = res.headers().get("Content-Type")
+            .map(|h| h.to_str().map(|s| s.to_string()))
+            .transpose()?;
+
+        // Streamed to a hidden file first, so that interrupted downloads never leave a truncated page behind
+        let part = PartFile::stream(res, &directory.join(format!(".{}.part", image.file_name()))).await?;
+
+        if verify && !image.verify_hash(&part.hash) {
+            part.discard().await;
+            return Err(ImageDownloadError::HashMismatch);
+        }
+
+        Ok((part, content_type))
+    }
+
+    // Processing can change extensions and page counts, so pages are named again once it's done
+    fn renumber(&self, master_path:&Path, layout:&Layout, processed:Vec<PathBuf>) -> Result<Vec<PathBuf>, std::io::Error> {
+        let count = processed.len();
+        let mut pages = Vec::with_capacity(count);
+
+        for (i, page) in processed.into_iter().enumerate() {
+            let extension = page.extension().unwrap_or_default().to_string_lossy().to_string();
+            let path = master_path.join(Path::new(&layout.page_path(&self.metadata, i + 1, count, &extension)));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
             }
+
+            fs::rename(&page, &path)?;
+            pages.push(path);
+        }
+
+        Ok(pages)
+    }
+
+    // Packs the pages into <folder>.cbz and removes the folder. Images are already compressed, so they're only stored.
+    fn pack(directory:&Path, pages:&[PathBuf]) -> Result<PathBuf, ImageDownloadError> {
+        let path = directory.with_file_name(format!("{}.cbz", directory.file_name().unwrap_or_default().to_string_lossy()));
+        let mut zip = zip::ZipWriter::new(File::create(&path)?);
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+
+        for page in pages.iter() {
+            let name = page.strip_prefix(directory).unwrap_or(page);
+            zip.start_file(name.to_string_lossy(), options)?;
+            zip.write_all(&fs::read(page)?)?;
+        }
+
+        zip.finish()?;
+        fs::remove_dir_all(directory)?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(id:&str, chapter:&str, group:&str) -> ChapterMetadata {
+        ChapterMetadata {
+            id: id.to_string(),
+            volume: String::from("1"),
+            chapter: chapter.to_string(),
+            title: String::new(),
+            language: String::from("en"),
+            group: group.to_string(),
+            group_ids: Vec::new(),
+            uploader: String::new(),
+            pages: 1,
+            version: 1,
+            publish_at: String::new(),
+            readable_at: String::new(),
+            external_url: None,
         }
+    }
+
+    fn groups(selected:&[&ChapterMetadata]) -> Vec<String> {
+        selected.iter().map(|m| m.group.clone()).collect()
+    }
+
+    #[test]
+    fn prefers_fewer_switches_to_preferred_group() {
+        let (b1, a2, b2, b3) = (upload("b1", "1", "B"), upload("a2", "2", "A"), upload("b2", "2", "B"), upload("b3", "3", "B"));
+        let chapters = vec![vec![&b1], vec![&a2, &b2], vec![&b3]];
+
+        assert_eq!(groups(&select_consistent(&chapters, "A")), ["B", "B", "B"]);
+    }
+
+    #[test]
+    fn breaks_ties_with_preferred_group() {
+        let (a1, b1, a2, b2) = (upload("a1", "1", "A"), upload("b1", "1", "B"), upload("a2", "2", "A"), upload("b2", "2", "B"));
+        let chapters = vec![vec![&a1, &b1], vec![&a2, &b2]];
+        assert_eq!(groups(&select_consistent(&chapters, "A")), ["A", "A"]);
+        assert_eq!(groups(&select_consistent(&chapters, "B")), ["B", "B"]);
+
+        // One switch is needed either way, so it's put off for as long as the preferred group has the chapter
+        let (a1, a2, b2, b3) = (upload("a1", "1", "A"), upload("a2", "2", "A"), upload("b2", "2", "B"), upload("b3", "3", "B"));
+        let chapters = vec![vec![&a1], vec![&a2, &b2], vec![&b3]];
+        assert_eq!(groups(&select_consistent(&chapters, "A")), ["A", "A", "B"]);
+        assert_eq!(groups(&select_consistent(&chapters, "B")), ["A", "B", "B"]);
+    }
+
+    #[test]
+    fn collapses_duplicate_uploads() {
+        let (a1, a1_again, b1) = (upload("a1", "1", "A"), upload("a1-again", "1", "A"), upload("b1", "1", "B"));
+        let (a2, b2, b2_again) = (upload("a2", "2", "A"), upload("b2", "2", "B"), upload("b2-again", "2", "B"));
+        let candidates = group_candidates(&[&a1, &a1_again, &b1]).iter().map(|m| m.id.clone()).collect::<Vec<String>>();
+        assert_eq!(candidates, ["a1", "b1"]);
+
+        let chapters = vec![vec![&a1, &a1_again, &b1], vec![&b2, &b2_again, &a2]];
+        let ids = select_consistent(&chapters, "B").iter().map(|m| m.id.clone()).collect::<Vec<String>>();
+        assert_eq!(ids, ["b1", "b2"]);
+        let ids = select_consistent(&chapters, "A").iter().map(|m| m.id.clone()).collect::<Vec<String>>();
+        assert_eq!(ids, ["a1", "a2"]);
+    }
+
+    #[test]
+    fn reports_only_forced_switches() {
+        let (a1, a2, b2, b3) = (upload("a1", "1", "A"), upload("a2", "2", "A"), upload("b2", "2", "B"), upload("b3", "3", "B"));
+        let series = ChapterMetadataSeries {
+            max_tl: String::from("B"),
+            max_tl_count: 2,
+            tl_group_counts: HashMap::new(),
+            chapters: vec![a1.clone(), a2.clone(), b2.clone(), b3.clone()],
+        };
+
+        // A could have carried on into chapter 2, but not into chapter 3
+        assert!(series.get_forced_switches(&[&a1, &b2, &b3]).is_empty());
+        let switches = series.get_forced_switches(&[&a1, &a2, &b3]);
+        assert_eq!(switches.iter().map(|(from, to)| (from.id.as_str(), to.id.as_str())).collect::<Vec<_>>(), [("a2", "b3")]);
+    }
+
+    #[test]
+    fn latest_goes_by_chapter_number() {
+        let published = |volume:&str, chapter:&str, day:u32| ChapterMetadata {
+            volume: volume.to_string(),
+            publish_at: format!("2020-01-{:02}T00:00:00+00:00", day),
+            ..upload(&format!("{}-{}", volume, chapter), chapter, "A")
+        };
+        let series = ChapterMetadataSeries {
+            max_tl: String::from("A"),
+            max_tl_count: 6,
+            tl_group_counts: HashMap::new(),
+            chapters: vec![
+                published("1", "1", 1),
+                published("1", "2", 2),
+                // An extra without a number, published between chapters 2 and 3
+                published("", "", 3),
+                published("", "3", 4),
+                published("", "4", 5),
+                // A late upload of an old chapter, which hasn't been given a volume
+                published("", "1.5", 6),
+            ],
+        };
+
+        let latest = |n:&str| series.get_download_metadata("en", &None, &Some(n.parse::<RangeSet>().unwrap()), TLSelectionStrategy::Frequency)
+            .iter()
+            .map(|m| m.chapter.clone())
+            .collect::<Vec<String>>();
 
-        Ok(pb)
+        assert_eq!(latest("latest:2"), ["3", "4"]);
+        // Still in reading order, which puts the extra last
+        assert_eq!(latest("latest:3"), ["3", "4", ""]);
+        assert_eq!(latest("latest:4"), ["2", "3", "4", ""]);
     }
 }
@@ -1,11 +1,18 @@
 use crate::chapter::ImageDownloadError;
+use crate::image::{ self, PartFile };
+use crate::layout::Layout;
 use crate::requester::RateLimitedRequester;
 use crate::types::CoverArtData;
 use crate::utils;
 
-use std::path::Path;
-use std::fs::{ self, File };
-use std::io::Write;
+use std::path::{ Path, PathBuf };
+
+/// A cover that has been written to disk.
+#[derive(Debug, Clone)]
+pub struct DownloadedCover {
+    pub volume: String,
+    pub path: PathBuf,
+}
 
 #[derive(Debug, Clone)]
 pub struct CoverArt {
@@ -21,41 +28,61 @@ impl CoverArt {
     }
 
     pub fn from_response(id:&str, mut raw:Vec<CoverArtData>) -> Vec<Self> {
-        raw.drain(..).map(|r| Self::from_data(id, r))
-            .filter(|ca| ca.is_some())
-            .map(|ca| ca.unwrap())
+        raw.drain(..).filter_map(|r| Self::from_data(id, r))
             .collect::<Vec<Self>>()
     }
 
-    pub fn get_volume(&self) -> String {
+    // Numbers are zero padded to width, e.g. Volume 01
+    pub fn get_volume(&self, width:usize) -> String {
         match self.volume.parse::<f64>() {
-            Ok(v) => format!("Volume {}", v),
+            Ok(v) => format!("Volume {}", utils::pad_number(v, width)),
             Err(_) => self.volume.clone(),
         }
     }
 
-    pub async fn download(&self, requester:&mut RateLimitedRequester, master_directory:&Path) -> Result<(), ImageDownloadError> {
-        let master_path = master_directory.join(Path::new(&utils::escape_path(&self.get_volume())));
-        fs::create_dir_all(&master_path)?;
+    // Downloads to wherever the layout puts volume covers
+    pub async fn download(&self, requester:&mut RateLimitedRequester, master_directory:&Path, layout:&Layout) -> Result<DownloadedCover, ImageDownloadError> {
+        let (part, extension) = self.fetch(requester, master_directory).await?;
+        self.save(part, master_directory.join(Path::new(&layout.cover_path(self, &extension)))).await
+    }
+
+    // Downloads as cover.<ext> in the given folder
+    pub async fn download_to(&self, requester:&mut RateLimitedRequester, master_path:&Path) -> Result<DownloadedCover, ImageDownloadError> {
+        let (part, extension) = self.fetch(requester, master_path).await?;
+        self.save(part, master_path.join(Path::new(&format!("cover.{}", extension)))).await
+    }
 
-        let res = requester.request("content", &format!("/covers/{}", &self.url)).await?;
+    // Streams the cover to a hidden file in the given folder, until it's known where it goes
+    async fn fetch(&self, requester:&mut RateLimitedRequester, directory:&Path) -> Result<(PartFile, String), ImageDownloadError> {
+        let res = requester.request("content", &format!("/covers/{}", self.url)).await?;
         let content_type = res.headers().get("Content-Type")
-            .ok_or(ImageDownloadError::NoContentType)?
-            .to_str()?;
+            .map(|h| h.to_str().map(|s| s.to_string()))
+            .transpose()?;
 
-        let extension = mime_guess::get_mime_extensions_str(content_type)
-            .ok_or(ImageDownloadError::Mime)?
-            .iter().map(|s| *s)
-            .next()
-            .ok_or(ImageDownloadError::Mime)?;
+        let file_name = Path::new(&self.url).file_name().unwrap_or_default().to_string_lossy();
+        let part = PartFile::stream(res, &directory.join(format!(".{}.part", file_name))).await?;
 
-        let body = res.bytes().await?;
+        // Cover file names carry an extension too, for anything that can't be told from its bytes or Content-Type
+        let extension = match image::extension(&part.head, content_type.as_deref(), &format!("Cover {}", self.url)) {
+            Ok(extension) => extension,
+            Err(e) => match Path::new(&self.url).extension() {
+                Some(extension) => extension.to_string_lossy().to_string(),
+                None => {
+                    part.discard().await;
+                    return Err(e);
+                },
+            },
+        };
 
-        // I'm too lazy to do async file io
-        let path = master_path.join(Path::new(&format!("cover.{}", extension)));
-        let mut file = File::create(path)?;
-        let _ = file.write_all(&body)?;
+        Ok((part, extension))
+    }
 
-        Ok(())
+    async fn save(&self, part:PartFile, path:PathBuf) -> Result<DownloadedCover, ImageDownloadError> {
+        part.persist(&path).await?;
+
+        Ok(DownloadedCover {
+            volume: self.volume.clone(),
+            path,
+        })
     }
 }
@@ -1,7 +1,15 @@
-use crate::chapter::ChapterError;
+use crate::chapter::{ ChapterError, ImageDownloadError };
 
+use std::path::{ Path, PathBuf };
+
+use log::warn;
 use regex::Regex;
+use reqwest::Response;
 use sha2::{ Digest, Sha256 };
+use tokio::io::AsyncWriteExt;
+
+// Enough of the start of a file to tell what kind of image it is
+const SNIFF_LENGTH:usize = 12;
 
 lazy_static! {
     static ref HASH_RE:Regex = Regex::new(r"([0-9a-fA-F]{64})").unwrap();
@@ -29,11 +37,196 @@ impl Image {
         &self.url
     }
 
-    pub fn verify(&self, body:&[u8]) -> bool {
+    // Name MangaDex gives the page, e.g. 1-<hash>.png
+    pub fn file_name(&self) -> &str {
+        self.url.rsplit('/').next().unwrap_or_default()
+    }
+
+    // SHA-256 the page should have
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    // Checks a hash of the page worked out while streaming it
+    pub fn verify_hash(&self, hash:&[u8]) -> bool {
+        hash == self.hash
+    }
+}
+
+/// A response body that has been streamed to a temporary file, and can be moved to where it belongs once it's been checked.
+#[derive(Debug)]
+pub struct PartFile {
+    pub path: PathBuf,
+    /// SHA-256 of the whole body
+    pub hash: Vec<u8>,
+    /// Start of the body, for telling what kind of image it is
+    pub head: Vec<u8>,
+}
+impl PartFile {
+    /// Streams the body of `res` to `path` as it arrives, hashing it on the way. Nothing is left at `path` if that fails.
+    pub async fn stream(res:Response, path:&Path) -> Result<Self, ImageDownloadError> {
+        match Self::write(res, path).await {
+            Ok(part) => Ok(part),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(path).await;
+                Err(e)
+            },
+        }
+    }
+
+    async fn write(mut res:Response, path:&Path) -> Result<Self, ImageDownloadError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut file = tokio::fs::File::create(path).await?;
         let mut hasher = Sha256::new();
-        hasher.update(body);
-        let result = hasher.finalize();
+        let mut head = Vec::with_capacity(SNIFF_LENGTH);
+
+        while let Some(chunk) = res.chunk().await? {
+            hasher.update(&chunk);
+            if head.len() < SNIFF_LENGTH {
+                head.extend_from_slice(&chunk[..chunk.len().min(SNIFF_LENGTH - head.len())]);
+            }
+
+            file.write_all(&chunk).await?;
+        }
+
+        // On disk before it's renamed into place, or a crash could leave a renamed but truncated page
+        file.flush().await?;
+        file.sync_all().await?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            hash: hasher.finalize().to_vec(),
+            head,
+        })
+    }
+
+    /// Moves the file to `path` in one go, so that it's either there in full or not at all.
+    pub async fn persist(self, path:&Path) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::rename(&self.path, path).await
+    }
+
+    pub async fn discard(self) {
+        let _ = tokio::fs::remove_file(&self.path).await;
+    }
+
+    /// Removes files left in `directory` by downloads that were interrupted, i.e. hidden ones ending in .part.
+    pub async fn clear_stale(directory:&Path) -> Result<(), std::io::Error> {
+        let mut entries = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') && name.ends_with(".part") && entry.file_type().await?.is_file() {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Image formats MangaDex serves, which can be told apart by their first few bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageKind {
+    JPEG,
+    PNG,
+    GIF,
+    WebP,
+    AVIF,
+}
+impl ImageKind {
+    // Needs at most the first 12 bytes
+    pub fn sniff(bytes:&[u8]) -> Option<Self> {
+        match bytes {
+            [0xff, 0xd8, 0xff, ..] => Some(ImageKind::JPEG),
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some(ImageKind::PNG),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageKind::GIF),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageKind::WebP),
+            // An ISO media file with an avif (still) or avis (sequence) brand
+            [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => Some(ImageKind::AVIF),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime:&str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or_default().trim().to_lowercase();
+        match mime.as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(ImageKind::JPEG),
+            "image/png" => Some(ImageKind::PNG),
+            "image/gif" => Some(ImageKind::GIF),
+            "image/webp" => Some(ImageKind::WebP),
+            "image/avif" => Some(ImageKind::AVIF),
+            _ => None,
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageKind::JPEG => "image/jpeg",
+            ImageKind::PNG => "image/png",
+            ImageKind::GIF => "image/gif",
+            ImageKind::WebP => "image/webp",
+            ImageKind::AVIF => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageKind::JPEG => "jpg",
+            ImageKind::PNG => "png",
+            ImageKind::GIF => "gif",
+            ImageKind::WebP => "webp",
+            ImageKind::AVIF => "avif",
+        }
+    }
+}
+
+/// Works out the extension to save an image as. The image's own bytes are trusted over its Content-Type, which is only used
+/// for formats that can't be told from their bytes. `name` is what the image is called in warnings.
+pub fn extension(bytes:&[u8], content_type:Option<&str>, name:&str) -> Result<String, ImageDownloadError> {
+    let declared = content_type.and_then(ImageKind::from_mime);
+    match (ImageKind::sniff(bytes), declared) {
+        (Some(kind), Some(declared)) if kind != declared => {
+            warn!("{} is served as {}, but is actually {}", name, declared.mime(), kind.mime());
+            Ok(kind.extension().to_string())
+        },
+        (Some(kind), _) | (None, Some(kind)) => Ok(kind.extension().to_string()),
+        (None, None) => {
+            let content_type = content_type.ok_or(ImageDownloadError::NoContentType)?;
+            mime_guess::get_mime_extensions_str(content_type)
+                .and_then(|extensions| extensions.first())
+                .map(|extension| extension.to_string())
+                .ok_or(ImageDownloadError::Mime)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_image_kinds() {
+        assert_eq!(ImageKind::sniff(b"\xff\xd8\xff\xdb"), Some(ImageKind::JPEG));
+        assert_eq!(ImageKind::sniff(b"\x89PNG\r\n\x1a\n"), Some(ImageKind::PNG));
+        assert_eq!(ImageKind::sniff(b"GIF89a"), Some(ImageKind::GIF));
+        assert_eq!(ImageKind::sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some(ImageKind::WebP));
+        assert_eq!(ImageKind::sniff(b"\x00\x00\x00\x1cftypavif"), Some(ImageKind::AVIF));
+        assert_eq!(ImageKind::sniff(b"\x89PNG"), None);
+        assert_eq!(ImageKind::sniff(b""), None);
+    }
 
-        result[..] == self.hash
+    #[test]
+    fn prefers_bytes_to_content_type() {
+        assert_eq!(extension(b"\xff\xd8\xff\xe0", Some("image/png"), "test").unwrap(), "jpg");
+        assert_eq!(extension(b"????", Some("image/jpeg; charset=binary"), "test").unwrap(), "jpg");
+        assert_eq!(extension(b"????", Some("image/bmp"), "test").unwrap(), "bmp");
+        assert!(matches!(extension(b"????", None, "test"), Err(ImageDownloadError::NoContentType)));
     }
 }
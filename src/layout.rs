@@ -0,0 +1,240 @@
+use crate::chapter::ChapterMetadata;
+use crate::coverart::CoverArt;
+use crate::sanitise::Sanitiser;
+use crate::template::{ Template, Templates, Variables };
+use crate::utils;
+
+use std::fmt;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LayoutError {
+    #[error("invalid padding '{0}': expected auto, none or a number of digits")]
+    Padding(String),
+}
+
+/// How far volume and chapter numbers are zero padded, so that file browsers sort them properly.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Padding {
+    /// Not at all, e.g. Chapter 5
+    None,
+    /// As far as the series' highest number needs, e.g. Chapter 005 in a series that reaches 120
+    Auto,
+    /// To a fixed number of digits
+    Width(usize),
+}
+impl Padding {
+    // Volume and chapter widths, given the series' highest volume and chapter numbers
+    pub fn widths(&self, max_volume:Option<f64>, max_chapter:Option<f64>) -> (usize, usize) {
+        let digits = |n:Option<f64>| n.map(|n| (n.floor().max(1.0)).log10().floor() as usize + 1).unwrap_or(1);
+        match self {
+            Padding::None => (0, 0),
+            Padding::Auto => (digits(max_volume), digits(max_chapter)),
+            Padding::Width(width) => (*width, *width),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = LayoutError;
+
+    fn from_str(s:&str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(Padding::None),
+            "auto" => Ok(Padding::Auto),
+            width => width.parse::<usize>()
+                .map(Padding::Width)
+                .map_err(|_| LayoutError::Padding(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Padding::None => write!(f, "none"),
+            Padding::Auto => write!(f, "auto"),
+            Padding::Width(width) => write!(f, "{}", width),
+        }
+    }
+}
+
+/// Where files are put, for readers that expect a particular folder structure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LayoutPreset {
+    /// Chapters in per-volume folders, e.g. "Volume 1/Chapter 5", with a cover in each volume folder
+    Default,
+    /// Tachiyomi/Mihon local source: flat "Vol.1 Ch.5" chapters, with cover.jpg and details.json in the series folder
+    Tachiyomi,
+    /// Komga/Kavita: flat "Series Vol.01 Ch.005.cbz" chapters, with cover.jpg and series.json in the series folder
+    Komga,
+}
+
+/// How chapters are named and stored in the series folder.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub preset: LayoutPreset,
+    /// Title of the series, which some presets put in chapter names
+    pub series: String,
+    /// Append chapter titles to chapter names
+    pub chapter_titles: bool,
+    /// Digits to zero pad volume numbers to
+    pub volume_width: usize,
+    /// Digits to zero pad chapter numbers to
+    pub chapter_width: usize,
+    /// Pack each chapter's pages into a CBZ instead of leaving them in a folder. Always done for Komga.
+    pub cbz: bool,
+    /// Override the preset's naming where given
+    pub templates: Templates,
+    /// Series variables for templates
+    pub variables: Variables,
+    /// Makes names safe, and keeps track of them so that different chapters never share one
+    pub sanitiser: Sanitiser,
+}
+impl Layout {
+    fn render(&self, template:&Template, extra:Variables) -> String {
+        let mut variables = self.variables.clone();
+        variables.extend(extra);
+        template.render(&variables, &self.sanitiser)
+    }
+
+    // Files are useless without an extension, so it's added if the template leaves it out
+    fn render_file(&self, template:&Template, mut extra:Variables, extension:&str) -> String {
+        extra.insert("ext", extension.to_string());
+        match template.uses("ext") {
+            true => self.render(template, extra),
+            false => format!("{}.{}", self.sanitiser.fit_path(&self.render(template, extra), extension.len() + 1), extension),
+        }
+    }
+
+    // Chapter folder, or CBZ without its extension, relative to the series folder
+    pub fn chapter_path(&self, chapter:&ChapterMetadata) -> String {
+        let path = match (&self.templates.chapter, self.preset) {
+            (Some(template), _) => self.render(template, Template::chapter_variables(chapter)),
+            (None, LayoutPreset::Default) => chapter.assemble_folder_name(self.chapter_titles, self.volume_width, self.chapter_width, &self.sanitiser),
+            (None, LayoutPreset::Tachiyomi) => self.tachiyomi_chapter_name(chapter),
+            (None, LayoutPreset::Komga) => self.komga_chapter_name(chapter),
+        };
+
+        // Leave room for .cbz or .url
+        self.sanitiser.claim("", &self.sanitiser.fit_path(&path, 4), &chapter.id)
+    }
+
+    // Komga and Kavita only read archives
+    pub fn archive(&self) -> bool {
+        self.cbz || self.preset == LayoutPreset::Komga
+    }
+
+    // Where the chapter ends up once downloaded, relative to the series folder
+    pub fn chapter_file(&self, chapter:&ChapterMetadata) -> String {
+        match self.archive() {
+            true => format!("{}.cbz", self.chapter_path(chapter)),
+            false => self.chapter_path(chapter),
+        }
+    }
+
+    // Page file, relative to the chapter folder. Pages are numbered from 1.
+    pub fn page_path(&self, chapter:&ChapterMetadata, page:usize, pages:usize, extension:&str) -> String {
+        let path = match &self.templates.page {
+            Some(template) => {
+                let mut extra = Template::chapter_variables(chapter);
+                extra.insert("page", page.to_string());
+                extra.insert("pages", pages.to_string());
+                self.render_file(template, extra, extension)
+            },
+            None => {
+                let digits = (pages as f64).log10().floor() as usize + 1;
+                format!("{:0digits$}.{}", page, extension, digits=digits)
+            },
+        };
+
+        self.sanitiser.claim(&self.chapter_path(chapter), &path, &format!("{} page {}", chapter.id, page))
+    }
+
+    // Volume cover file, relative to the series folder
+    pub fn cover_path(&self, cover:&CoverArt, extension:&str) -> String {
+        let path = match &self.templates.cover {
+            Some(template) => {
+                let mut extra = Variables::new();
+                extra.insert("volume", cover.volume.clone());
+                self.render_file(template, extra, extension)
+            },
+            None => format!("{}/cover.{}", self.sanitiser.sanitise(&cover.get_volume(self.volume_width)), extension),
+        };
+
+        self.sanitiser.claim("", &path, &format!("cover {}", cover.volume))
+    }
+
+    // Metadata file, relative to the series folder
+    pub fn metadata_path(&self, extension:&str) -> String {
+        let path = match &self.templates.metadata {
+            Some(template) => self.render_file(template, Variables::new(), extension),
+            None => format!("metadata.{}", extension),
+        };
+
+        self.sanitiser.claim("", &path, "metadata")
+    }
+
+    // Tachiyomi reads chapters from a flat folder, and recognises chapter numbers by the "Ch." in front of them
+    fn tachiyomi_chapter_name(&self, chapter:&ChapterMetadata) -> String {
+        let volume = match chapter.volume.parse::<f64>() {
+            Ok(v) => format!("Vol.{}", utils::pad_number(v, self.volume_width)),
+            Err(_) => chapter.volume.clone(),
+        };
+
+        let number = match chapter.chapter.parse::<f64>() {
+            Ok(c) => format!("Ch.{}", utils::pad_number(c, self.chapter_width)),
+            Err(_) => chapter.chapter.clone(),
+        };
+
+        let name = match (volume.is_empty(), number.is_empty()) {
+            (false, false) => format!("{} {}", volume, number),
+            (true, false) => number,
+            (false, true) => volume,
+            (true, true) => String::from("Oneshot"),
+        };
+
+        match (self.chapter_titles, chapter.title.is_empty()) {
+            (true, false) => self.sanitiser.sanitise(&format!("{} - {}", name, chapter.title)),
+            _ => self.sanitiser.sanitise(&name),
+        }
+    }
+
+    // Komga and Kavita parse "Vol." and "Ch." out of file names, and sort the rest as text, so numbers are always zero padded
+    fn komga_chapter_name(&self, chapter:&ChapterMetadata) -> String {
+        let volume = match chapter.volume.parse::<f64>() {
+            Ok(v) => format!("Vol.{}", utils::pad_number(v, self.volume_width.max(2))),
+            Err(_) => chapter.volume.clone(),
+        };
+
+        let number = match chapter.chapter.parse::<f64>() {
+            Ok(c) => format!("Ch.{}", utils::pad_number(c, self.chapter_width.max(3))),
+            Err(_) => chapter.chapter.clone(),
+        };
+
+        let name = match (volume.is_empty(), number.is_empty()) {
+            (false, false) => format!("{} {} {}", self.series, volume, number),
+            (true, false) => format!("{} {}", self.series, number),
+            (false, true) => format!("{} {}", self.series, volume),
+            (true, true) => format!("{} Oneshot", self.series),
+        };
+
+        match (self.chapter_titles, chapter.title.is_empty()) {
+            (true, false) => self.sanitiser.sanitise(&format!("{} - {}", name, chapter.title)),
+            _ => self.sanitiser.sanitise(&name),
+        }
+    }
+
+    // Whether volume covers are saved at all
+    pub fn volume_covers(&self) -> bool {
+        self.preset == LayoutPreset::Default || self.templates.cover.is_some()
+    }
+
+    // Whether the manga's main cover goes into the series folder
+    pub fn series_cover(&self) -> bool {
+        self.preset != LayoutPreset::Default
+    }
+}
@@ -0,0 +1,526 @@
+//! Downloads manga, along with their cover art and metadata, from [MangaDex](https://mangadex.org).
+//!
+//! The simplest way in is [`download`], which does everything the CLI does:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), mangadex_downloader::DownloadError> {
+//! use mangadex_downloader::{ download, API, DownloadOptions };
+//!
+//! let mut api = API::builder().build()?;
+//! let options = DownloadOptions { language: String::from("en"), ..DownloadOptions::default() };
+//! let report = download(&mut api, "https://mangadex.org/title/348966d0-c807-45cf-9260-8adf006a9da6", &options).await?;
+//! println!("Downloaded {} chapter(s) to {}", report.chapters.len(), report.directory.display());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`resolve`] and [`download_selection`] split that into working out what to download, and downloading it.
+//! The building blocks they use are available through [`API`] and the modules below.
+#[macro_use]
+extern crate lazy_static;
+
+pub mod api;
+pub mod chapter;
+pub mod coverart;
+pub mod image;
+pub mod layout;
+pub mod manga;
+pub mod manifest;
+pub mod metadata;
+pub mod process;
+pub mod range;
+pub mod ratelimits;
+pub mod requester;
+pub mod sanitise;
+pub mod template;
+pub mod types;
+pub mod utils;
+
+pub use api::{ API, APIBuilder, APIError, FeedFilter };
+pub use chapter::{ ChapterMetadata, DownloadedChapter };
+pub use coverart::DownloadedCover;
+pub use layout::{ Layout, LayoutError, LayoutPreset, Padding };
+pub use manga::MangaMetadata;
+pub use manifest::{ Manifest, ManifestError, PageProblem, ProblemKind, VerifyReport };
+pub use metadata::{ Metadata, MetadataError, MylarSeries, TachiyomiDetails };
+pub use process::{ ConvertFormat, ProcessError, ProcessOptions, Processor, SpreadMode };
+pub use range::{ RangeError, RangeSet };
+pub use sanitise::{ SanitiseMode, Sanitiser };
+pub use template::{ Template, TemplateError, TemplateKind, Templates };
+
+use coverart::CoverArt;
+use manifest::ChapterRecord;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use clap::ValueEnum;
+use log::{ info, warn };
+use serde::{ Deserialize, Serialize };
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum MetadataOutputFormat {
+    TOML,
+    JSON,
+}
+impl MetadataOutputFormat {
+    pub fn file_format(&self) -> &'static str {
+        match self {
+            MetadataOutputFormat::TOML => "toml",
+            MetadataOutputFormat::JSON => "json",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum TLSelectionStrategy {
+    /// Pick the preferred (or most frequent) TL-group for each chapter independently
+    Frequency,
+    /// Minimise the number of TL-group switches across the downloaded chapters
+    Consistent,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum ContentRating {
+    Safe,
+    Suggestive,
+    Erotica,
+    Pornographic,
+}
+impl ContentRating {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentRating::Safe => "safe",
+            ContentRating::Suggestive => "suggestive",
+            ContentRating::Erotica => "erotica",
+            ContentRating::Pornographic => "pornographic",
+        }
+    }
+
+    pub fn all() -> Vec<Self> {
+        vec![ContentRating::Safe, ContentRating::Suggestive, ContentRating::Erotica, ContentRating::Pornographic]
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("{0}")]
+    API(#[from] APIError),
+    #[error("{0}")]
+    Range(#[from] RangeError),
+    #[error("{0}")]
+    Template(#[from] TemplateError),
+    #[error("{0}")]
+    Layout(#[from] LayoutError),
+    #[error("{0}")]
+    Process(#[from] ProcessError),
+    #[error("{0}")]
+    Manifest(#[from] ManifestError),
+    #[error("specified language is not available")]
+    LanguageNotAvailable,
+    #[error("no title is available")]
+    TitleNotAvailable,
+    #[error("{0}")]
+    Metadata(#[from] MetadataError),
+    #[error("error writing file: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// What to download, and how. The defaults match the CLI's.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Preferred download language, in ISO-639 form
+    pub language: String,
+    /// The manga is saved to a subfolder of this
+    pub output_dir: PathBuf,
+    /// Chapters to download; everything if `None`
+    pub ranges: Option<RangeSet>,
+    /// Title languages to save to the metadata file. `all` saves every title.
+    pub metadata_title_languages: Vec<String>,
+    /// Format to save metadata in; metadata isn't saved if `None`
+    pub metadata_file_format: Option<MetadataOutputFormat>,
+    /// Write .url shortcuts for externally hosted chapters
+    pub external_shortcuts: bool,
+    /// Folder structure to save into
+    pub layout: LayoutPreset,
+    /// Append chapter titles to chapter folder names
+    pub chapter_titles: bool,
+    /// How far to zero pad volume and chapter numbers in names
+    pub padding: Padding,
+    /// Pack each chapter into a CBZ. Always done for the Komga layout.
+    pub cbz: bool,
+    /// Path templates, which override the layout's naming where given
+    pub templates: Templates,
+    /// Filesystems names have to be valid on
+    pub sanitise_mode: SanitiseMode,
+    /// Longest file or folder name allowed, in bytes. Longer names are shortened, with a hash to keep them unique.
+    pub max_name_length: usize,
+    /// Conversion and other changes made to pages once downloaded
+    pub processing: ProcessOptions,
+    /// Pages processed at once. 0 uses one per CPU.
+    pub workers: usize,
+    /// Check pages against the hashes MangaDex gives them
+    pub verify: bool,
+    /// Name of the preferred TL-group. The most frequent one is preferred otherwise.
+    pub preferred_tl: Option<String>,
+    pub tl_strategy: TLSelectionStrategy,
+    /// Infer missing volume numbers from neighbouring chapters
+    pub infer_volumes: bool,
+    /// IDs of TL-groups to never download from
+    pub excluded_groups: Vec<String>,
+    pub content_ratings: Vec<ContentRating>,
+    /// Don't draw progress bars on stdout. On by default, so that embedding the library doesn't print anything; other
+    /// progress is logged through `log`.
+    pub quiet: bool,
+}
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            language: String::from("en"),
+            output_dir: PathBuf::from("output"),
+            ranges: None,
+            metadata_title_languages: vec![String::from("ja-ro"), String::from("ja"), String::from("en")],
+            metadata_file_format: Some(MetadataOutputFormat::TOML),
+            external_shortcuts: false,
+            layout: LayoutPreset::Default,
+            chapter_titles: false,
+            padding: Padding::Auto,
+            cbz: false,
+            templates: Templates::default(),
+            sanitise_mode: SanitiseMode::Windows,
+            max_name_length: 143,
+            processing: ProcessOptions::default(),
+            workers: 0,
+            verify: true,
+            preferred_tl: None,
+            tl_strategy: TLSelectionStrategy::Frequency,
+            infer_volumes: false,
+            excluded_groups: Vec::new(),
+            content_ratings: ContentRating::all(),
+            quiet: true,
+        }
+    }
+}
+
+impl DownloadOptions {
+    pub fn layout(&self, selection:&Selection) -> Layout {
+        let (volume_width, chapter_width) = self.padding.widths(selection.max_volume, selection.max_chapter);
+        Layout {
+            preset: self.layout,
+            series: selection.title.clone(),
+            chapter_titles: self.chapter_titles,
+            volume_width,
+            chapter_width,
+            cbz: self.cbz,
+            templates: self.templates.clone(),
+            variables: Template::series_variables(&selection.manga, &selection.title, &self.language),
+            sanitiser: self.sanitiser(),
+        }
+    }
+
+    pub fn sanitiser(&self) -> Sanitiser {
+        Sanitiser::new(self.sanitise_mode, self.max_name_length)
+    }
+
+    // Nothing if pages are left as downloaded
+    pub fn processor(&self, manga:&MangaMetadata) -> Result<Option<Processor>, ProcessError> {
+        let processing = ProcessOptions {
+            stitch: self.processing.stitch && manga.is_long_strip(),
+            right_to_left: manga.right_to_left(),
+            ..self.processing.clone()
+        };
+
+        if !processing.is_active() {
+            return Ok(None);
+        }
+
+        let workers = match self.workers {
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            workers => workers,
+        };
+
+        Processor::new(processing, workers).map(Some)
+    }
+
+    // Folder the manga is saved to
+    pub fn series_directory(&self, manga:&MangaMetadata, title:&str) -> PathBuf {
+        match &self.templates.series {
+            Some(template) => self.output_dir.join(Path::new(&template.render(&Template::series_variables(manga, title, &self.language), &self.sanitiser()))),
+            None => self.output_dir.join(Path::new(&self.sanitiser().sanitise(title))),
+        }
+    }
+}
+
+/// The chapters of a manga that are to be downloaded, and where to.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub manga: MangaMetadata,
+    pub title: String,
+    /// Folder the manga will be saved to
+    pub directory: PathBuf,
+    /// Selected chapters, in reading order
+    pub chapters: Vec<ChapterMetadata>,
+    /// Highest volume number in the series, not just the selection
+    pub max_volume: Option<f64>,
+    /// Highest chapter number in the series, not just the selection
+    pub max_chapter: Option<f64>,
+}
+
+/// Everything that was saved by a download.
+#[derive(Debug, Clone)]
+pub struct DownloadReport {
+    pub title: String,
+    /// Folder the manga was saved to
+    pub directory: PathBuf,
+    pub chapters: Vec<DownloadedChapter>,
+    pub covers: Vec<DownloadedCover>,
+    /// Chapters that were skipped because they aren't hosted on MangaDex
+    pub skipped: Vec<ChapterMetadata>,
+    pub metadata_file: Option<PathBuf>,
+    /// Metadata for the layout's reader: details.json for Tachiyomi, series.json for Komga
+    pub details_file: Option<PathBuf>,
+    /// Record of every page saved, for checking them later
+    pub manifest_file: Option<PathBuf>,
+}
+
+// Volumes are strings such as "1" or "01", so compare them numerically where possible
+fn is_same_volume(a:&str, b:&str) -> bool {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Works out which chapters of the manga at `url` should be downloaded, without downloading anything.
+pub async fn resolve(api:&mut API, url:&str, options:&DownloadOptions) -> Result<Selection, DownloadError> {
+    info!("Retrieving metadata...");
+    let manga_metadata = api.get_manga_metadata(url).await?;
+
+    if !manga_metadata.languages.iter().any(|lang| lang == &options.language) {
+        return Err(DownloadError::LanguageNotAvailable);
+    }
+
+    let title = manga_metadata.get_title(&options.language).ok_or(DownloadError::TitleNotAvailable)?;
+
+    info!("Retrieving chapter metadata...");
+    let feed_filter = FeedFilter {
+        languages: vec![options.language.clone()],
+        excluded_groups: options.excluded_groups.clone(),
+        content_ratings: options.content_ratings.clone(),
+    };
+    let mut chapter_metadata = api.get_chapter_metadata(&manga_metadata, &feed_filter, options.quiet).await?;
+    if options.infer_volumes {
+        let inferred = chapter_metadata.infer_volumes();
+        info!("Inferred volumes for {} chapter(s)", inferred);
+    }
+
+    let download_chapter_metadata = chapter_metadata.get_download_metadata(&options.language, &options.preferred_tl, &options.ranges, options.tl_strategy);
+    if let TLSelectionStrategy::Consistent = options.tl_strategy {
+        for (from, to) in chapter_metadata.get_forced_switches(&download_chapter_metadata) {
+            info!("Switching TL-group from '{}' to '{}' at volume {} chapter {}", from.group, to.group, to.volume, to.chapter);
+        }
+    }
+
+    // Pad to the whole series, so that names stay the same when later chapters are downloaded
+    let (max_volume, max_chapter) = chapter_metadata.get_max_numbers();
+    let last_volume = manga_metadata.last_volume.as_ref().and_then(|v| v.parse::<f64>().ok());
+    let last_chapter = manga_metadata.last_chapter.as_ref().and_then(|c| c.parse::<f64>().ok());
+
+    Ok(Selection {
+        max_volume: max_volume.into_iter().chain(last_volume).reduce(f64::max),
+        max_chapter: max_chapter.into_iter().chain(last_chapter).reduce(f64::max),
+        directory: options.series_directory(&manga_metadata, &title),
+        title,
+        chapters: download_chapter_metadata.into_iter().cloned().collect(),
+        manga: manga_metadata,
+    })
+}
+
+/// Downloads the chapters in `selection`, along with their covers and the manga's metadata.
+pub async fn download_selection(api:&mut API, selection:&Selection, options:&DownloadOptions) -> Result<DownloadReport, DownloadError> {
+    let master_directory = &selection.directory;
+    let layout = options.layout(selection);
+    let processor = options.processor(&selection.manga)?;
+
+    // Externally hosted chapters (e.g. on official publisher sites) have no pages on MangaDex
+    let (external_chapter_metadata, download_chapter_metadata):(Vec<&ChapterMetadata>, Vec<&ChapterMetadata>) = selection.chapters.iter()
+        .partition(|m| m.is_external() || m.pages == 0);
+
+    for m in external_chapter_metadata.iter() {
+        match &m.external_url {
+            Some(url) => warn!("Skipping volume {} chapter {}: hosted externally at {}", m.volume, m.chapter, url),
+            None => warn!("Skipping volume {} chapter {}: no pages available", m.volume, m.chapter),
+        }
+    }
+
+    if options.external_shortcuts {
+        for m in external_chapter_metadata.iter() {
+            m.write_shortcut(master_directory, &layout)?;
+        }
+    }
+
+    info!("Retrieving cover art metadata...");
+    let cover_art_metadata = api.get_cover_art(&selection.manga.id, options.quiet).await?;
+
+    info!("Retrieving chapter images download data...");
+    let chapters = api.get_chapters(&download_chapter_metadata, options.quiet).await?;
+
+    info!("Downloading chapters...");
+    let downloaded_chapters = api.download_chapters(&chapters, master_directory, &layout, processor.as_ref(), options.verify, options.quiet).await?;
+
+    info!("Downloading cover art...");
+    let download_cover_arts = cover_art_metadata.iter()
+        .filter(|_| layout.volume_covers())
+        .filter(|cam| options.ranges.as_ref().map(|r| r.in_volume_range(&cam.volume) || chapters.iter().any(|c| is_same_volume(&c.metadata.volume, &cam.volume))).unwrap_or(true))
+        .cloned()
+        .collect::<Vec<CoverArt>>();
+    let mut downloaded_covers = api.download_cover_art(&download_cover_arts, master_directory, &layout, options.quiet).await?;
+
+    if layout.series_cover() {
+        match &selection.manga.cover_art {
+            Some(cover_art) => downloaded_covers.push(api.download_series_cover(cover_art, master_directory).await?),
+            None => warn!("Manga has no main cover"),
+        }
+    }
+
+    let metadata_file = match options.metadata_file_format {
+        Some(format) => {
+            info!("Saving metadata...");
+            let metadata = Metadata::new(&selection.manga, &options.language, &options.metadata_title_languages, &download_chapter_metadata);
+            Some(metadata.save(&master_directory.join(Path::new(&layout.metadata_path(format.file_format()))), format)?)
+        },
+        None => None,
+    };
+
+    let details_file = match layout.preset {
+        LayoutPreset::Tachiyomi => {
+            fs::create_dir_all(master_directory)?;
+            Some(TachiyomiDetails::new(&selection.manga, &options.language).save(master_directory)?)
+        },
+        LayoutPreset::Komga => {
+            fs::create_dir_all(master_directory)?;
+            Some(MylarSeries::new(&selection.manga, &options.language).save(master_directory)?)
+        },
+        LayoutPreset::Default => None,
+    };
+
+    let manifest_file = match downloaded_chapters.is_empty() {
+        true => None,
+        false => {
+            let mut manifest = Manifest::load_or_new(master_directory, &selection.manga.id)?;
+            for chapter in downloaded_chapters.iter() {
+                manifest.record(master_directory, chapter)?;
+            }
+
+            Some(manifest.save(master_directory)?)
+        },
+    };
+
+    Ok(DownloadReport {
+        title: selection.title.clone(),
+        directory: master_directory.clone(),
+        chapters: downloaded_chapters,
+        covers: downloaded_covers,
+        skipped: external_chapter_metadata.into_iter().cloned().collect(),
+        metadata_file,
+        details_file,
+        manifest_file,
+    })
+}
+
+/// Downloads the manga at `url`; [`resolve`] followed by [`download_selection`].
+pub async fn download(api:&mut API, url:&str, options:&DownloadOptions) -> Result<DownloadReport, DownloadError> {
+    let selection = resolve(api, url, options).await?;
+    download_selection(api, &selection, options).await
+}
+
+/// Checks the series downloaded to `directory` against its manifest. With `refetch`, missing and corrupt pages that were saved
+/// unchanged are downloaded again; processed pages can only be fixed by downloading their chapter again.
+pub async fn verify(api:&mut API, directory:&Path, refetch:bool) -> Result<VerifyReport, DownloadError> {
+    let manifest = Manifest::load(directory)?;
+    let mut problems = manifest.check(directory)?;
+
+    if refetch {
+        for (c, record) in manifest.chapters.iter().enumerate() {
+            let pages = problems.iter()
+                .filter(|p| p.chapter == c)
+                .map(|p| p.page)
+                .collect::<Vec<usize>>();
+
+            if pages.is_empty() {
+                continue;
+            }
+
+            let refetched = refetch_pages(api, directory, record, &pages).await?;
+            for problem in problems.iter_mut().filter(|p| p.chapter == c && refetched.contains(&p.page)) {
+                problem.refetched = true;
+            }
+        }
+    }
+
+    Ok(VerifyReport {
+        checked: manifest.page_count(),
+        manifest,
+        problems,
+    })
+}
+
+// Returns the pages that were fixed
+async fn refetch_pages(api:&mut API, directory:&Path, record:&ChapterRecord, pages:&[usize]) -> Result<Vec<usize>, DownloadError> {
+    let chapter = match api.get_chapters(&[&record.metadata], true).await?.pop() {
+        Some(chapter) => chapter,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut downloaded = HashMap::new();
+    for page in pages.iter() {
+        let source = match &record.pages[*page].source {
+            Some(source) => source,
+            None => {
+                warn!("Can't refetch {}: it was processed, so download the chapter again instead", record.pages[*page].path);
+                continue;
+            },
+        };
+
+        // Matched by hash, as file names change if a chapter is uploaded again
+        let image = match chapter.urls.iter().find(|i| hex::encode(i.hash()) == source.sha256) {
+            Some(image) => image,
+            None => {
+                warn!("Can't refetch {}: MangaDex no longer has it", record.pages[*page].path);
+                continue;
+            },
+        };
+
+        match api.download_page(&chapter, image, directory).await {
+            Ok(part) => {
+                downloaded.insert(*page, part);
+            },
+            Err(e) => warn!("Can't refetch {}: {}", record.pages[*page].path, e),
+        }
+    }
+
+    match &record.archive {
+        Some(archive) => {
+            let replacements = downloaded.iter()
+                .map(|(page, part)| (*page, part.path.clone()))
+                .collect::<HashMap<usize, PathBuf>>();
+            manifest::repack(&directory.join(archive), record, &replacements)?;
+            for part in downloaded.values() {
+                fs::remove_file(&part.path)?;
+            }
+        },
+        None => for (page, part) in downloaded.iter() {
+            let path = directory.join(&record.pages[*page].path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::rename(&part.path, path)?;
+        },
+    }
+
+    Ok(downloaded.into_keys().collect())
+}
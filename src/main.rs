@@ -1,61 +1,39 @@
-#![feature(iter_intersperse)]
-#[macro_use]
-extern crate lazy_static;
-
-mod api;
-mod chapter;
-mod coverart;
-mod image;
-mod manga;
-mod metadata;
-mod range;
-mod ratelimits;
-mod requester;
-mod types;
-mod utils;
-
-use api::{ API, APIError };
-use coverart::CoverArt;
-use metadata::{ Metadata, MetadataError };
-use range::{ Range, RangeError };
-
-use std::path::Path;
-
-use clap::{ Parser, ValueEnum };
-use log::{ info, error };
-use simplelog::{ self, TermLogger, LevelFilter, TerminalMode, ColorChoice };
-use thiserror::Error;
+use mangadex_downloader::{ self as downloader, API, ChapterMetadata, ContentRating, ConvertFormat, DownloadError, DownloadOptions, LayoutPreset, MetadataOutputFormat, Padding, ProblemKind, ProcessOptions, RangeSet, SanitiseMode, SpreadMode, Template, TemplateKind, Templates, TLSelectionStrategy };
+use mangadex_downloader::requester::{ DEFAULT_API_URL, DEFAULT_UPLOADS_URL };
 
-#[derive(Debug, Copy, Clone, ValueEnum)]
-pub enum MetadataOutputFormat {
-    TOML,
-    JSON,
-}
-impl MetadataOutputFormat {
-    pub fn file_format(&self) -> &'static str {
-        match self {
-            MetadataOutputFormat::TOML => "toml",
-            MetadataOutputFormat::JSON => "json",
-        }
-    }
-}
+use std::path::{ Path, PathBuf };
+
+use clap::{ Parser, Subcommand };
+use log::{ info, error, warn };
+use simplelog::{ self, TermLogger, LevelFilter, TerminalMode, ColorChoice };
 
 #[derive(Debug, Parser)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
 pub struct Arguments {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// The URL to the manga, e.g. https://mangadex.org/title/348966d0-c807-45cf-9260-8adf006a9da6/kono-bijutsubu-ni-wa-mondai-ga-aru
     url: Option<String>,
     #[clap(short, long, default_value = "en")]
     /// Preferred download language, in ISO-639 form, e.g. en
     language: String,
+    #[clap(long, default_value = DEFAULT_API_URL)]
+    /// Base URL of the MangaDex API
+    api_url: String,
+    #[clap(long, default_value = DEFAULT_UPLOADS_URL)]
+    /// Base URL MangaDex serves cover art from
+    uploads_url: String,
     #[clap(long)]
     /// Display metadata only; do not download
     metadata: bool,
+    #[clap(long, visible_alias = "list")]
+    /// List the chapters that would be downloaded and where they would be saved; do not download
+    dry_run: bool,
     #[clap(short, long, default_value = "output")]
     /// Output directory. Manga will be created as a subfolder to this.
     output_dir: String,
     #[clap(short, long)]
-    /// Chapter range to download, leave blank to download the whole manga.
+    /// Chapter range to download, e.g. 1,9:55-12:68 (see the README for the full format). Leave blank to download the whole manga.
     range: Option<String>,
     #[clap(short, long)]
     /// Suppress all terminal output
@@ -70,22 +48,192 @@ pub struct Arguments {
     /// Don't save metadata
     no_metadata: bool,
     #[clap(long)]
+    /// Write a .url shortcut to the official source for chapters that are hosted externally. These are skipped otherwise.
+    external_shortcuts: bool,
+    #[clap(long, value_enum, default_value_t=LayoutPreset::Default)]
+    /// Folder structure to save into. 'tachiyomi' matches Tachiyomi/Mihon's local source; 'komga' suits Komga and Kavita libraries.
+    layout: LayoutPreset,
+    #[clap(long)]
+    /// Append chapter titles to chapter folder names, e.g. "Chapter 10 - The Title"
+    chapter_titles: bool,
+    #[clap(long, default_value_t=Padding::Auto)]
+    /// Zero pad volume and chapter numbers so they sort properly: 'auto' pads as far as the series' highest number needs, 'none' doesn't pad, or give a number of digits
+    padding: Padding,
+    #[clap(long)]
+    /// Pack each chapter into a CBZ archive instead of a folder of images
+    cbz: bool,
+    #[clap(long, value_enum, default_value_t=SanitiseMode::Windows)]
+    /// Filesystems file and folder names have to be valid on. 'windows' is also safe for most NAS shares.
+    sanitise: SanitiseMode,
+    #[clap(long, default_value_t=143)]
+    /// Longest file or folder name allowed, in bytes. Longer names are shortened, with a hash to keep them unique.
+    max_name_length: usize,
+    #[clap(long)]
+    /// Template for the series folder, relative to the output directory, e.g. "{title} ({year})". See the README for variables.
+    series_template: Option<String>,
+    #[clap(long)]
+    /// Template for chapter folders, relative to the series folder, e.g. "Vol.{volume:02}/Ch.{chapter:04} [{group}]"
+    chapter_template: Option<String>,
+    #[clap(long)]
+    /// Template for page files, relative to the chapter folder, e.g. "{page:03}.{ext}"
+    page_template: Option<String>,
+    #[clap(long)]
+    /// Template for volume cover files, relative to the series folder, e.g. "covers/{volume:02}.{ext}"
+    cover_template: Option<String>,
+    #[clap(long)]
+    /// Template for the metadata file, relative to the series folder, e.g. "{title}.{ext}"
+    metadata_template: Option<String>,
+    #[clap(long, value_enum)]
+    /// Convert pages to this format once downloaded. 'avif' and 'jxl' need the matching build features.
+    convert: Option<ConvertFormat>,
+    #[clap(long, default_value_t=85, value_parser=clap::value_parser!(u8).range(1..=100))]
+    /// Quality of converted or otherwise re-encoded pages, from 1 to 100
+    quality: u8,
+    #[clap(long)]
+    /// Scale pages taller than this many pixels down to it
+    max_height: Option<u32>,
+    #[clap(long)]
+    /// Convert pages to grayscale, e.g. for e-ink readers
+    grayscale: bool,
+    #[clap(long, value_enum)]
+    /// Split landscape pages (double-page spreads) into two, in reading order, or rotate them to fill a portrait screen
+    spreads: Option<SpreadMode>,
+    #[clap(long)]
+    /// Trim uniform white or black margins from pages
+    crop: bool,
+    #[clap(long, default_value_t=16)]
+    /// How far margin pixels may be from pure white or black, from 0 to 255
+    crop_tolerance: u8,
+    #[clap(long, default_value_t=0.5)]
+    /// Don't crop pages if less than this share of their width or height would be left, from 0 to 1
+    crop_min_content: f32,
+    #[clap(long)]
+    /// For long strip (webtoon) series, stitch each chapter's pages together and slice them again at gutters, into pages of about --slice-height
+    stitch: bool,
+    #[clap(long, default_value_t=2000)]
+    /// Height, in pixels, that slices of stitched chapters aim for
+    slice_height: u32,
+    #[clap(long, default_value_t=0)]
+    /// Number of pages to process at once. 0 uses one per CPU.
+    workers: usize,
+    #[clap(long)]
+    /// Don't check pages against the hashes MangaDex gives them
+    no_verify: bool,
+    #[clap(long)]
     /// Name of the preferred translation group. If not supplied, TL-group will be selected based on frequency.
     preferred_tl: Option<String>,
+    #[clap(long)]
+    /// Infer missing volume numbers from neighbouring chapters before applying ranges and naming folders
+    infer_volumes: bool,
+    #[clap(long)]
+    /// ID of a TL-group whose chapters should never be downloaded. May be given multiple times.
+    exclude_group: Vec<String>,
+    #[clap(long, value_enum, default_values=&["safe", "suggestive", "erotica", "pornographic"])]
+    /// Content ratings of chapters to download.
+    content_rating: Vec<ContentRating>,
+    #[clap(long, value_enum, default_value_t=TLSelectionStrategy::Frequency)]
+    /// How to choose between TL-groups when a chapter has been translated by more than one.
+    tl_strategy: TLSelectionStrategy,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Check a downloaded series against the manifest saved with it, and report missing or corrupt pages
+    Verify {
+        /// Series folder, e.g. output/Kono Bijutsubu ni wa Mondai ga Aru!
+        dir: PathBuf,
+        #[clap(long)]
+        /// Download missing and corrupt pages again. Only pages saved unchanged can be; processed ones need their chapter downloading again.
+        refetch: bool,
+        #[clap(long, default_value = DEFAULT_API_URL)]
+        /// Base URL of the MangaDex API
+        api_url: String,
+        #[clap(short, long)]
+        /// Only report problems
+        quiet: bool,
+    },
+}
+
+impl Arguments {
+    fn to_options(&self) -> Result<DownloadOptions, DownloadError> {
+        Ok(DownloadOptions {
+            language: self.language.clone(),
+            output_dir: PathBuf::from(&self.output_dir),
+            ranges: self.range.as_ref().map(|r| r.parse::<RangeSet>()).transpose()?,
+            metadata_title_languages: self.metadata_title_languages.clone(),
+            metadata_file_format: match self.no_metadata {
+                true => None,
+                false => Some(self.metadata_file_format),
+            },
+            external_shortcuts: self.external_shortcuts,
+            layout: self.layout,
+            chapter_titles: self.chapter_titles,
+            padding: self.padding,
+            cbz: self.cbz,
+            templates: Templates {
+                series: self.series_template.as_ref().map(|t| Template::parse(t, TemplateKind::Series)).transpose()?,
+                chapter: self.chapter_template.as_ref().map(|t| Template::parse(t, TemplateKind::Chapter)).transpose()?,
+                page: self.page_template.as_ref().map(|t| Template::parse(t, TemplateKind::Page)).transpose()?,
+                cover: self.cover_template.as_ref().map(|t| Template::parse(t, TemplateKind::Cover)).transpose()?,
+                metadata: self.metadata_template.as_ref().map(|t| Template::parse(t, TemplateKind::Metadata)).transpose()?,
+            },
+            sanitise_mode: self.sanitise,
+            max_name_length: self.max_name_length,
+            processing: ProcessOptions {
+                format: self.convert,
+                quality: self.quality,
+                max_height: self.max_height,
+                grayscale: self.grayscale,
+                spreads: self.spreads,
+                crop: self.crop,
+                crop_tolerance: self.crop_tolerance,
+                crop_min_content: self.crop_min_content,
+                stitch: self.stitch,
+                slice_height: self.slice_height,
+                ..ProcessOptions::default()
+            },
+            workers: self.workers,
+            verify: !self.no_verify,
+            preferred_tl: self.preferred_tl.clone(),
+            tl_strategy: self.tl_strategy,
+            infer_volumes: self.infer_volumes,
+            excluded_groups: self.exclude_group.clone(),
+            content_ratings: self.content_rating.clone(),
+            quiet: self.quiet,
+        })
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Arguments::parse();
 
-    let log_level = match args.quiet {
+    let quiet = match &args.command {
+        Some(Command::Verify { quiet, .. }) => *quiet,
+        None => args.quiet,
+    };
+
+    let log_level = match quiet {
         true => LevelFilter::Warn,
         false => LevelFilter::Info,
     };
 
     TermLogger::init(log_level, simplelog::Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
 
-    if args.url == None {
+    if let Some(Command::Verify { dir, refetch, api_url, .. }) = &args.command {
+        match verify(dir, *refetch, api_url).await {
+            Ok(true) => info!("Done!"),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+
+        return;
+    }
+
+    if args.url.is_none() {
         error!("Manga url has not been specified");
         std::process::exit(1);
     }
@@ -98,66 +246,70 @@ async fn main() {
     info!("Done!");
 }
 
-#[derive(Debug, Error)]
-enum ProgramError {
-    #[error("{0}")]
-    API(#[from] APIError),
-    #[error("{0}")]
-    Range(#[from] RangeError),
-    #[error("specified language is not available")]
-    LanguageNotAvailable,
-    #[error("no title is available")]
-    TitleNotAvailable,
-    #[error("{0}")]
-    Metadata(#[from] MetadataError),
+// Whether every page is intact, or has been fixed
+async fn verify(dir:&Path, refetch:bool, api_url:&str) -> Result<bool, DownloadError> {
+    let mut api = API::builder()
+        .api_url(api_url)
+        .build()?;
+
+    info!("Verifying {}...", dir.display());
+    let report = downloader::verify(&mut api, dir, refetch).await?;
+
+    for problem in report.problems.iter() {
+        let chapter = &report.manifest.chapters[problem.chapter].metadata;
+        let kind = match problem.kind {
+            ProblemKind::Missing => "missing",
+            ProblemKind::Corrupt => "corrupt",
+        };
+
+        match problem.refetched {
+            true => info!("Volume {} chapter {}: {} was {}; refetched", chapter.volume, chapter.chapter, report.describe(problem), kind),
+            false => warn!("Volume {} chapter {}: {} is {}", chapter.volume, chapter.chapter, report.describe(problem), kind),
+        }
+    }
+
+    let remaining = report.problems.iter().filter(|p| !p.refetched).count();
+    info!("{} page(s) checked, {} problem(s) found, {} left", report.checked, report.problems.len(), remaining);
+
+    Ok(remaining == 0)
 }
 
-async fn run(args:Arguments) -> Result<(), ProgramError> {
-    let url = args.url.unwrap();
-    let mut api = API::new();
+async fn run(args:Arguments) -> Result<(), DownloadError> {
+    let url = args.url.as_ref().unwrap();
+    let options = args.to_options()?;
+    let mut api = API::builder()
+        .api_url(&args.api_url)
+        .uploads_url(&args.uploads_url)
+        .build()?;
 
-    info!("Retrieving metadata...");
-    let manga_metadata = api.get_manga_metadata(&url).await?;
     if args.metadata {
+        info!("Retrieving metadata...");
+        let manga_metadata = api.get_manga_metadata(url).await?;
         manga_metadata.print();
         return Ok(());
     }
 
-    if !manga_metadata.languages.iter().any(|lang| lang == &args.language) {
-        return Err(ProgramError::LanguageNotAvailable);
+    let selection = downloader::resolve(&mut api, url, &options).await?;
+    if args.dry_run {
+        let chapters = selection.chapters.iter().collect::<Vec<&ChapterMetadata>>();
+        ChapterMetadata::print_table(&chapters, &selection.directory, &options.layout(&selection));
+        return Ok(());
     }
 
-    let ranges = args.range.map(|r| Range::from_str(&r))
-        .transpose()?;
-
-    let title = manga_metadata.get_title(&args.language).ok_or(ProgramError::TitleNotAvailable)?;
-
-    info!("Retrieving chapter metadata...");
-    let chapter_metadata = api.get_chapter_metadata(&manga_metadata, args.quiet).await?;
-
-    info!("Retrieving cover art metadata...");
-    let cover_art_metadata = api.get_cover_art(&manga_metadata.id, args.quiet).await?;
+    downloader::download_selection(&mut api, &selection, &options).await?;
 
-    info!("Retrieving chapter images download data...");
-    let download_chapter_metadata = chapter_metadata.get_download_metadata(&args.language, &args.preferred_tl, &ranges);
-    let chapters = api.get_chapters(&download_chapter_metadata, args.quiet).await?;
-
-    info!("Downloading chapters...");
-    let master_directory = Path::new(&args.output_dir).join(Path::new(&utils::escape_path(&title)));
-    api.download_chapters(&chapters, &master_directory, args.quiet).await?;
+    Ok(())
+}
 
-    info!("Downloading cover art...");
-    let download_cover_arts = cover_art_metadata.iter()
-        .filter(|cam| ranges.as_ref().map(|r| r.iter().any(|range| range.in_volume_range(&cam.volume))).unwrap_or(true))
-        .map(|cam| cam.clone())
-        .collect::<Vec<CoverArt>>();
-    api.download_cover_art(&download_cover_arts, &master_directory, args.quiet).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if !args.no_metadata {
-        info!("Saving metadata...");
-        let metadata = Metadata::new(&manga_metadata, &args.language, &args.metadata_title_languages);
-        metadata.save(&master_directory, args.metadata_file_format)?;
+    #[test]
+    fn parses_convert_formats() {
+        for (name, format) in [("jpeg", ConvertFormat::JPEG), ("webp", ConvertFormat::WebP), ("avif", ConvertFormat::AVIF), ("jxl", ConvertFormat::JXL)] {
+            let args = Arguments::try_parse_from(["mangadex-downloader", "--convert", name]).unwrap();
+            assert_eq!(args.convert, Some(format));
+        }
     }
-
-    Ok(())
 }
@@ -1,5 +1,7 @@
-use crate::types::MangaDataResponse;
-use std::collections::HashMap;
+use crate::ContentRating;
+use crate::coverart::CoverArt;
+use crate::types::{ Demographic, MangaDataResponse, MangaStatus, RawContentRating, Relationship };
+use std::collections::{ BTreeMap, HashMap };
 
 #[derive(Debug, Clone)]
 pub struct MangaMetadata {
@@ -9,12 +11,24 @@ pub struct MangaMetadata {
     pub languages: Vec<String>,
     pub descriptions: HashMap<String, String>,
     pub tags: Vec<HashMap<String, String>>,
+    pub authors: Vec<String>,
+    pub artists: Vec<String>,
+    pub status: Option<MangaStatus>,
+    pub year: Option<u64>,
+    pub demographic: Option<Demographic>,
+    pub original_language: Option<String>,
+    pub content_rating: Option<ContentRating>,
+    pub last_volume: Option<String>,
+    pub last_chapter: Option<String>,
+    // Site name -> URL
+    pub links: BTreeMap<String, String>,
+    // Main cover, if the relationship was expanded
+    pub cover_art: Option<CoverArt>,
 }
 impl MangaMetadata {
     pub fn from_response(id:String, raw:MangaDataResponse) -> Self {
         let alt_titles = raw.data.attributes.alt_titles.iter()
-            .map(|at| at.iter())
-            .flatten()
+            .flat_map(|at| at.iter())
             .fold(HashMap::new(), |mut acc:HashMap<String, Vec<String>>, (lang, title)| {
                 if let Some(ats) = acc.get_mut(lang) {
                     ats.push(title.to_string());
@@ -34,16 +48,93 @@ impl MangaMetadata {
             .map(|t| t.attributes.name.clone())
             .collect::<Vec<HashMap<String, String>>>();
 
+        // Authors and artists only have names if the relationships were expanded with includes[]
+        let authors = raw.data.relationships.iter()
+            .filter_map(|r| match r {
+                Relationship::Author { attributes: Some(attributes), .. } => Some(attributes.name.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let artists = raw.data.relationships.iter()
+            .filter_map(|r| match r {
+                Relationship::Artist { attributes: Some(attributes), .. } => Some(attributes.name.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let cover_art = raw.data.relationships.iter()
+            .find_map(|r| match r {
+                Relationship::CoverArt { attributes: Some(attributes), .. } => Some(CoverArt {
+                    volume: attributes.volume.clone().unwrap_or_default(),
+                    url: format!("{}/{}", id, attributes.file_name),
+                }),
+                _ => None,
+            });
+
+        let links = raw.data.attributes.links.iter()
+            .flatten()
+            .filter_map(|(site, value)| Self::expand_link(site, value))
+            .collect::<BTreeMap<String, String>>();
+
+        let attributes = raw.data.attributes;
         Self {
             id,
-            titles: raw.data.attributes.title,
+            titles: attributes.title,
             alt_titles,
             languages,
-            descriptions: raw.data.attributes.description,
+            descriptions: attributes.description,
             tags,
+            authors,
+            artists,
+            status: attributes.status,
+            year: attributes.year,
+            demographic: attributes.publication_demographic,
+            original_language: attributes.original_language,
+            content_rating: attributes.content_rating.and_then(content_rating),
+            last_volume: attributes.last_volume.filter(|v| !v.is_empty()),
+            last_chapter: attributes.last_chapter.filter(|c| !c.is_empty()),
+            links,
+            cover_art,
         }
     }
 
+    // MangaDex stores most links as IDs on the external site. Unknown sites are dropped.
+    fn expand_link(site:&str, value:&str) -> Option<(String, String)> {
+        let (name, url) = match site {
+            "al" => ("anilist", format!("https://anilist.co/manga/{}", value)),
+            "ap" => ("anime_planet", format!("https://www.anime-planet.com/manga/{}", value)),
+            "bw" => ("bookwalker", format!("https://bookwalker.jp/{}", value)),
+            "kt" => ("kitsu", format!("https://kitsu.app/manga/{}", value)),
+            "mal" => ("myanimelist", format!("https://myanimelist.net/manga/{}", value)),
+            // Old MangaUpdates IDs are numeric, new ones are base 36
+            "mu" => match value.chars().all(|c| c.is_ascii_digit()) {
+                true => ("mangaupdates", format!("https://www.mangaupdates.com/series.html?id={}", value)),
+                false => ("mangaupdates", format!("https://www.mangaupdates.com/series/{}", value)),
+            },
+            "nu" => ("novelupdates", format!("https://www.novelupdates.com/series/{}", value)),
+            "amz" => ("amazon", value.to_string()),
+            "cdj" => ("cdjapan", value.to_string()),
+            "ebj" => ("ebookjapan", value.to_string()),
+            "raw" => ("official_raw", value.to_string()),
+            "engtl" => ("official_english", value.to_string()),
+            _ => return None,
+        };
+
+        Some((name.to_string(), url))
+    }
+
+    // Korean and Chinese comics, and anything originally in English, read left to right; manga and most everything else
+    // on MangaDex read right to left
+    pub fn right_to_left(&self) -> bool {
+        !matches!(self.original_language.as_deref(), Some("ko" | "zh" | "zh-hk" | "en"))
+    }
+
+    // Webtoons and other vertical scrolling comics
+    pub fn is_long_strip(&self) -> bool {
+        self.tags.iter().any(|t| t.values().any(|name| name.eq_ignore_ascii_case("Long Strip")))
+    }
+
     pub fn get_title(&self, preferred_language:&str) -> Option<String> {
         match self.titles.get(preferred_language) {
             Some(title) => Some(title.to_string()),
@@ -63,37 +154,101 @@ impl MangaMetadata {
 
         let titles = self.titles.iter()
             .map(|(lang, title)| format!("\t- {} ({})", title, lang))
-            .intersperse("\n".to_string())
-            .collect::<String>();
+            .collect::<Vec<_>>()
+            .join("\n");
 
         println!("Titles:",);
         println!("{}", titles);
 
         let alt_titles = self.alt_titles.iter()
-            .map(|(lang, titles)| titles.iter().map(|title| format!("\t- {} ({})", title, lang.clone())))
-            .flatten()
-            .intersperse("\n".to_string())
-            .collect::<String>();
+            .flat_map(|(lang, titles)| titles.iter().map(|title| format!("\t- {} ({})", title, lang.clone())))
+            .collect::<Vec<_>>()
+            .join("\n");
 
         println!("Alternative Titles:");
         println!("{}", alt_titles);
 
         let tags = self.tags.iter()
-            .map(|t| t.iter().map(|(_, v)| v))
-            .flatten()
+            .flat_map(|t| t.values())
             .map(|t| t.to_string())
-            .intersperse(", ".to_string())
-            .collect::<String>();
+            .collect::<Vec<_>>()
+            .join(", ");
 
         println!("Tags: {}", tags);
 
+        if !self.authors.is_empty() {
+            println!("Authors: {}", self.authors.join(", "));
+        }
+
+        if !self.artists.is_empty() {
+            println!("Artists: {}", self.artists.join(", "));
+        }
+
+        if let Some(status) = &self.status {
+            println!("Status: {}", status.as_str());
+        }
+
+        if let Some(year) = self.year {
+            println!("Year: {}", year);
+        }
+
+        if let Some(demographic) = &self.demographic {
+            println!("Demographic: {}", demographic.as_str());
+        }
+
+        if let Some(content_rating) = &self.content_rating {
+            println!("Content Rating: {}", content_rating.as_str());
+        }
+
+        if !self.links.is_empty() {
+            println!("Links:");
+            for (site, url) in self.links.iter() {
+                println!("\t- {}: {}", site, url);
+            }
+        }
+
         println!("Available Languages: {}", self.languages.join(", "));
 
         println!("-");
         println!("Descriptions:");
         for (language, description) in self.descriptions.iter() {
             println!("({}) {}", language, description);
-            println!("");
+            println!();
         }
     }
 }
+
+// Ratings MangaDex has added since are left out rather than guessed at
+fn content_rating(raw:RawContentRating) -> Option<ContentRating> {
+    match raw {
+        RawContentRating::Safe => Some(ContentRating::Safe),
+        RawContentRating::Suggestive => Some(ContentRating::Suggestive),
+        RawContentRating::Erotica => Some(ContentRating::Erotica),
+        RawContentRating::Pornographic => Some(ContentRating::Pornographic),
+        RawContentRating::Unknown => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_rating(content_rating:&str) -> MangaMetadata {
+        let raw = serde_json::from_value::<MangaDataResponse>(serde_json::json!({
+            "result": "ok",
+            "data": {
+                "id": "manga",
+                "type": "manga",
+                "attributes": { "title": { "en": "Test Manga" }, "contentRating": content_rating },
+            },
+        })).unwrap();
+
+        MangaMetadata::from_response(String::from("manga"), raw)
+    }
+
+    #[test]
+    fn tolerates_new_content_ratings() {
+        assert_eq!(with_rating("erotica").content_rating, Some(ContentRating::Erotica));
+        assert_eq!(with_rating("kids").content_rating, None);
+    }
+}
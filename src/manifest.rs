@@ -0,0 +1,278 @@
+use crate::chapter::{ ChapterMetadata, DownloadedChapter };
+
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::path::{ Path, PathBuf };
+
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use thiserror::Error;
+use zip::ZipArchive;
+
+pub const MANIFEST_FILE:&str = "manifest.json";
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("no manifest found at {0}")]
+    NotFound(PathBuf),
+    #[error("error reading or writing manifest: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("error (de)serialising manifest: {0}")]
+    JSON(#[from] serde_json::Error),
+    #[error("error reading or writing cbz: {0}")]
+    Archive(#[from] zip::result::ZipError),
+}
+
+/// Where every downloaded page was saved and what it hashed to, so that a series can be checked for pages that have gone
+/// missing or been corrupted since. Saved as manifest.json in the series folder.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub manga_id: String,
+    pub chapters: Vec<ChapterRecord>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChapterRecord {
+    pub metadata: ChapterMetadata,
+    /// CBZ the pages were packed into, relative to the series folder
+    pub archive: Option<String>,
+    pub pages: Vec<PageRecord>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PageRecord {
+    /// Relative to the series folder, or the name inside the chapter's CBZ
+    pub path: String,
+    /// SHA-256 of the page as saved, in hex. Only processed pages are checked against it.
+    pub sha256: String,
+    /// The page on MangaDex, if it was saved unchanged, i.e. not processed
+    pub source: Option<PageSource>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PageSource {
+    pub file_name: String,
+    /// SHA-256 MangaDex gives the page, in hex
+    pub sha256: String,
+}
+
+impl PageRecord {
+    // What the page should hash to. Pages saved unchanged go by MangaDex's hash, which also catches pages that were
+    // already corrupt when they were downloaded without verification.
+    pub fn expected_sha256(&self) -> &str {
+        match &self.source {
+            Some(source) => &source.sha256,
+            None => &self.sha256,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    Missing,
+    Corrupt,
+}
+
+/// A page that doesn't match the manifest.
+#[derive(Debug, Clone)]
+pub struct PageProblem {
+    /// Index into the manifest's chapters
+    pub chapter: usize,
+    /// Index into the chapter's pages
+    pub page: usize,
+    pub kind: ProblemKind,
+    /// Whether the page has since been downloaded again
+    pub refetched: bool,
+}
+
+/// The outcome of checking a series against its manifest.
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub manifest: Manifest,
+    /// Number of pages checked
+    pub checked: usize,
+    pub problems: Vec<PageProblem>,
+}
+impl VerifyReport {
+    // Where the page is, e.g. "Volume 1/Chapter 1/1.png" or "Series Ch.001.cbz: 1.png"
+    pub fn describe(&self, problem:&PageProblem) -> String {
+        let chapter = &self.manifest.chapters[problem.chapter];
+        let page = &chapter.pages[problem.page];
+        match &chapter.archive {
+            Some(archive) => format!("{}: {}", archive, page.path),
+            None => page.path.clone(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(manga_id:&str) -> Self {
+        Self {
+            manga_id: manga_id.to_string(),
+            chapters: Vec::new(),
+        }
+    }
+
+    pub fn load(series_directory:&Path) -> Result<Self, ManifestError> {
+        let path = series_directory.join(MANIFEST_FILE);
+        match fs::read_to_string(&path) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ManifestError::NotFound(path)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Adds to an existing manifest, so that downloading more chapters later doesn't lose track of earlier ones
+    pub fn load_or_new(series_directory:&Path, manga_id:&str) -> Result<Self, ManifestError> {
+        match Self::load(series_directory) {
+            Ok(manifest) if manifest.manga_id == manga_id => Ok(manifest),
+            Ok(_) | Err(ManifestError::NotFound(_)) => Ok(Self::new(manga_id)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, series_directory:&Path) -> Result<PathBuf, ManifestError> {
+        fs::create_dir_all(series_directory)?;
+        let path = series_directory.join(MANIFEST_FILE);
+        let mut file = File::create(&path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(path)
+    }
+
+    /// Records a downloaded chapter, replacing any earlier record of it. Pages are hashed as they are on disk.
+    pub fn record(&mut self, series_directory:&Path, chapter:&DownloadedChapter) -> Result<(), ManifestError> {
+        let sources = chapter.sources.as_ref()
+            .filter(|sources| sources.len() == chapter.pages.len());
+
+        let mut pages = Vec::with_capacity(chapter.pages.len());
+        let mut archive = match &chapter.archive {
+            Some(archive) => Some(ZipArchive::new(File::open(archive)?)?),
+            None => None,
+        };
+
+        for (i, page) in chapter.pages.iter().enumerate() {
+            let (path, sha256) = match &mut archive {
+                Some(archive) => {
+                    let name = relative(page, &chapter.directory);
+                    let sha256 = hash(archive.by_name(&name)?)?;
+                    (name, sha256)
+                },
+                None => (relative(page, series_directory), hash(File::open(page)?)?),
+            };
+
+            pages.push(PageRecord {
+                path,
+                sha256,
+                source: sources.map(|sources| PageSource {
+                    file_name: sources[i].file_name().to_string(),
+                    sha256: hex::encode(sources[i].hash()),
+                }),
+            });
+        }
+
+        let record = ChapterRecord {
+            metadata: chapter.metadata.clone(),
+            archive: chapter.archive.as_ref().map(|a| relative(a, series_directory)),
+            pages,
+        };
+
+        match self.chapters.iter_mut().find(|c| c.metadata.id == chapter.metadata.id) {
+            Some(existing) => *existing = record,
+            None => self.chapters.push(record),
+        }
+
+        Ok(())
+    }
+
+    /// Checks every page against its recorded hash.
+    pub fn check(&self, series_directory:&Path) -> Result<Vec<PageProblem>, ManifestError> {
+        let mut problems = Vec::new();
+        for (c, chapter) in self.chapters.iter().enumerate() {
+            let mut problem = |page:usize, kind:ProblemKind| problems.push(PageProblem { chapter: c, page, kind, refetched: false });
+
+            match &chapter.archive {
+                Some(archive) => {
+                    let mut archive = match File::open(series_directory.join(archive)) {
+                        Ok(file) => match ZipArchive::new(file) {
+                            Ok(archive) => archive,
+                            // Nothing in it can be trusted
+                            Err(_) => {
+                                (0..chapter.pages.len()).for_each(|p| problem(p, ProblemKind::Corrupt));
+                                continue;
+                            },
+                        },
+                        Err(_) => {
+                            (0..chapter.pages.len()).for_each(|p| problem(p, ProblemKind::Missing));
+                            continue;
+                        },
+                    };
+
+                    for (p, page) in chapter.pages.iter().enumerate() {
+                        match archive.by_name(&page.path) {
+                            // Reading an entry to the end also checks its CRC
+                            Ok(entry) => if hash(entry).ok().as_deref() != Some(page.expected_sha256()) {
+                                problem(p, ProblemKind::Corrupt);
+                            },
+                            Err(_) => problem(p, ProblemKind::Missing),
+                        }
+                    }
+                },
+                None => for (p, page) in chapter.pages.iter().enumerate() {
+                    match File::open(series_directory.join(&page.path)) {
+                        Ok(file) => if hash(file).ok().as_deref() != Some(page.expected_sha256()) {
+                            problem(p, ProblemKind::Corrupt);
+                        },
+                        Err(_) => problem(p, ProblemKind::Missing),
+                    }
+                },
+            }
+        }
+
+        Ok(problems)
+    }
+
+    // Number of pages recorded
+    pub fn page_count(&self) -> usize {
+        self.chapters.iter().map(|c| c.pages.len()).sum()
+    }
+}
+
+/// Rewrites a chapter's CBZ with some of its pages replaced by the files given, keyed by page index. Other pages are copied
+/// over from the old archive where they can be.
+pub fn repack(archive:&Path, chapter:&ChapterRecord, replacements:&HashMap<usize, PathBuf>) -> Result<(), ManifestError> {
+    let mut old = File::open(archive).ok().and_then(|f| ZipArchive::new(f).ok());
+    let part = archive.with_file_name(format!(".{}.part", archive.file_name().unwrap_or_default().to_string_lossy()));
+    let mut zip = zip::ZipWriter::new(File::create(&part)?);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored);
+
+    for (i, page) in chapter.pages.iter().enumerate() {
+        match (replacements.get(&i), &mut old) {
+            (Some(replacement), _) => {
+                zip.start_file(&page.path, options)?;
+                io::copy(&mut File::open(replacement)?, &mut zip)?;
+            },
+            (None, Some(old)) => if let Ok(entry) = old.by_name(&page.path) {
+                zip.raw_copy_file(entry)?;
+            },
+            (None, None) => (),
+        }
+    }
+
+    zip.finish()?;
+    fs::rename(&part, archive)?;
+
+    Ok(())
+}
+
+fn hash(mut reader:impl Read) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn relative(path:&Path, base:&Path) -> String {
+    path.strip_prefix(base).unwrap_or(path).to_string_lossy().to_string()
+}
@@ -1,11 +1,14 @@
-use crate::MetadataOutputFormat;
+use crate::{ ContentRating, MetadataOutputFormat };
+use crate::chapter::ChapterMetadata;
 use crate::manga::MangaMetadata;
+use crate::types::{ Demographic, MangaStatus };
 use serde::{ Deserialize, Serialize };
 use thiserror::Error;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
 
 #[derive(Debug, Error)]
 pub enum MetadataError {
@@ -23,19 +26,33 @@ pub struct Metadata {
     alt_titles: Vec<String>,
     description: String,
     tags: Vec<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    artists: Vec<String>,
+    status: Option<MangaStatus>,
+    year: Option<u64>,
+    demographic: Option<Demographic>,
+    original_language: Option<String>,
+    content_rating: Option<ContentRating>,
+    last_volume: Option<String>,
+    last_chapter: Option<String>,
+    // Tables have to come after plain values for TOML
+    #[serde(default)]
+    links: BTreeMap<String, String>,
+    #[serde(default)]
+    chapters: Vec<ChapterMetadata>,
 }
 impl Metadata {
-    pub fn new(metadata:&MangaMetadata, preferred_language:&str, metadata_title_languages:&[String]) -> Self {
+    pub fn new(metadata:&MangaMetadata, preferred_language:&str, metadata_title_languages:&[String], chapters:&[&ChapterMetadata]) -> Self {
         let alt_titles = match metadata_title_languages.iter().any(|o| o == "all") {
-            true => metadata.alt_titles.iter()
-                .map(|(_, v)| v.iter())
+            true => metadata.alt_titles.values()
                 .flatten()
                 .map(|s| s.to_string())
                 .collect::<Vec<String>>(),
             false => metadata.alt_titles.iter()
                 .filter(|(k, _)| metadata_title_languages.iter().any(|l| l == *k))
-                .map(|(_, v)| v.iter())
-                .flatten()
+                .flat_map(|(_, v)| v.iter())
                 .map(|s| s.to_string())
                 .collect::<Vec<String>>(),
         };
@@ -46,22 +63,163 @@ impl Metadata {
             .collect::<Vec<String>>();
 
         Self {
-            title: metadata.get_title(preferred_language).unwrap_or(String::new()),
+            title: metadata.get_title(preferred_language).unwrap_or_default(),
             alt_titles,
-            description: metadata.get_description(preferred_language).unwrap_or(String::new()),
+            description: metadata.get_description(preferred_language).unwrap_or_default(),
             tags,
+            authors: metadata.authors.clone(),
+            artists: metadata.artists.clone(),
+            status: metadata.status,
+            year: metadata.year,
+            demographic: metadata.demographic,
+            original_language: metadata.original_language.clone(),
+            content_rating: metadata.content_rating,
+            last_volume: metadata.last_volume.clone(),
+            last_chapter: metadata.last_chapter.clone(),
+            links: metadata.links.clone(),
+            chapters: chapters.iter().map(|c| (*c).clone()).collect::<Vec<ChapterMetadata>>(),
         }
     }
 
-    pub fn save(&self, master_directory:&Path, format:MetadataOutputFormat) -> Result<(), MetadataError> {
+    pub fn save(&self, path:&Path, format:MetadataOutputFormat) -> Result<PathBuf, MetadataError> {
         let data = match format {
             MetadataOutputFormat::TOML => toml::to_string(self)?,
             MetadataOutputFormat::JSON => serde_json::to_string(self)?,
         };
 
-        let mut file = File::create(master_directory.join(Path::new(&format!("metadata.{}", format.file_format()))))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::create(path)?;
         file.write_all(data.as_bytes())?;
 
-        Ok(())
+        Ok(path.to_path_buf())
+    }
+}
+
+// details.json, as read by Tachiyomi/Mihon's local source
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TachiyomiDetails {
+    title: String,
+    author: String,
+    artist: String,
+    description: String,
+    genre: Vec<String>,
+    // 0 = unknown, 1 = ongoing, 2 = completed, 3 = licensed, 4 = publishing finished, 5 = cancelled, 6 = on hiatus
+    status: String,
+}
+impl TachiyomiDetails {
+    pub fn new(metadata:&MangaMetadata, preferred_language:&str) -> Self {
+        let genre = metadata.tags.iter()
+            .filter_map(|t| t.get(preferred_language))
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+
+        let status = match metadata.status {
+            Some(MangaStatus::Ongoing) => "1",
+            Some(MangaStatus::Completed) => "2",
+            Some(MangaStatus::Cancelled) => "5",
+            Some(MangaStatus::Hiatus) => "6",
+            Some(MangaStatus::Unknown) | None => "0",
+        };
+
+        Self {
+            title: metadata.get_title(preferred_language).unwrap_or_default(),
+            author: metadata.authors.join(", "),
+            artist: metadata.artists.join(", "),
+            description: metadata.get_description(preferred_language).unwrap_or_default(),
+            genre,
+            status: status.to_string(),
+        }
+    }
+
+    pub fn save(&self, master_directory:&Path) -> Result<PathBuf, MetadataError> {
+        let path = master_directory.join(Path::new("details.json"));
+        let mut file = File::create(&path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(path)
+    }
+}
+
+// series.json, in the format Mylar writes and Komga/Kavita read
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MylarSeries {
+    version: String,
+    metadata: MylarSeriesMetadata,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MylarSeriesMetadata {
+    #[serde(rename="type")]
+    kind: String,
+    publisher: Option<String>,
+    imprint: Option<String>,
+    name: String,
+    comicid: Option<u64>,
+    year: Option<u64>,
+    description_text: String,
+    description_formatted: Option<String>,
+    volume: Option<u64>,
+    booktype: String,
+    age_rating: Option<String>,
+    collects: Option<Vec<String>>,
+    #[serde(rename="ComicImage")]
+    comic_image: Option<String>,
+    total_issues: Option<u64>,
+    publication_run: Option<String>,
+    // Continuing or Ended
+    status: String,
+}
+impl MylarSeries {
+    pub fn new(metadata:&MangaMetadata, preferred_language:&str) -> Self {
+        let status = match metadata.status {
+            Some(MangaStatus::Completed) | Some(MangaStatus::Cancelled) => "Ended",
+            _ => "Continuing",
+        };
+
+        let age_rating = metadata.content_rating.map(|cr| match cr {
+            ContentRating::Safe => "Everyone",
+            ContentRating::Suggestive => "Teen",
+            ContentRating::Erotica => "Mature 17+",
+            ContentRating::Pornographic => "Adult",
+        }.to_string());
+
+        // Only known once the series has finished
+        let total_issues = match status {
+            "Ended" => metadata.last_chapter.as_ref().and_then(|c| c.parse::<u64>().ok()),
+            _ => None,
+        };
+
+        Self {
+            version: String::from("1.0.2"),
+            metadata: MylarSeriesMetadata {
+                kind: String::from("comicSeries"),
+                publisher: None,
+                imprint: None,
+                name: metadata.get_title(preferred_language).unwrap_or_default(),
+                comicid: None,
+                year: metadata.year,
+                description_text: metadata.get_description(preferred_language).unwrap_or_default(),
+                description_formatted: None,
+                volume: None,
+                booktype: String::from("Print"),
+                age_rating,
+                collects: None,
+                comic_image: None,
+                total_issues,
+                publication_run: None,
+                status: status.to_string(),
+            },
+        }
+    }
+
+    pub fn save(&self, master_directory:&Path) -> Result<PathBuf, MetadataError> {
+        let path = master_directory.join(Path::new("series.json"));
+        let mut file = File::create(&path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(path)
     }
 }
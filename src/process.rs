@@ -0,0 +1,510 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use ::image::{ DynamicImage, ImageFormat, ImageOutputFormat, Rgb, RgbImage };
+use ::image::codecs::webp::{ WebPEncoder, WebPQuality };
+use ::image::imageops::{ self, FilterType };
+use clap::ValueEnum;
+use log::warn;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("error reading or writing image: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("error encoding image: {0}")]
+    Image(#[from] ::image::ImageError),
+    #[error("{0} output isn't supported by this build; rebuild with the '{1}' feature")]
+    Unsupported(&'static str, &'static str),
+    #[error("error encoding JPEG XL: {0}")]
+    JXL(String),
+    #[error("image processing worker failed: {0}")]
+    Worker(#[from] tokio::task::JoinError),
+}
+
+// Share of a border's pixels that may stray from its colour, for specks and scanner noise
+const CROP_NOISE:f32 = 0.005;
+
+/// Image format to convert pages to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ConvertFormat {
+    JPEG,
+    #[clap(name = "webp")]
+    WebP,
+    /// Needs the 'avif' feature
+    AVIF,
+    /// Needs the 'jxl' feature, and libjxl
+    JXL,
+}
+impl ConvertFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConvertFormat::JPEG => "jpg",
+            ConvertFormat::WebP => "webp",
+            ConvertFormat::AVIF => "avif",
+            ConvertFormat::JXL => "jxl",
+        }
+    }
+
+    fn check_supported(&self) -> Result<(), ProcessError> {
+        match self {
+            ConvertFormat::AVIF if !cfg!(feature = "avif") => Err(ProcessError::Unsupported("AVIF", "avif")),
+            ConvertFormat::JXL if !cfg!(feature = "jxl") => Err(ProcessError::Unsupported("JPEG XL", "jxl")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What to do with double-page spreads, i.e. landscape pages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SpreadMode {
+    /// Split into two pages, in reading order
+    Split,
+    /// Rotate so that the page reads top to bottom, filling a portrait screen
+    Rotate,
+}
+
+/// What to do to each page after it's been downloaded and verified.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// Format to convert pages to. Pages that are processed in other ways are re-encoded as JPEG or PNG, whichever they were.
+    pub format: Option<ConvertFormat>,
+    /// Lossy encoding quality, from 1 to 100
+    pub quality: u8,
+    /// Pages taller than this many pixels are scaled down to it
+    pub max_height: Option<u32>,
+    pub grayscale: bool,
+    /// Split or rotate landscape pages
+    pub spreads: Option<SpreadMode>,
+    /// Trim uniform white or black margins
+    pub crop: bool,
+    /// How far border pixels may be from pure white or black, from 0 to 255
+    pub crop_tolerance: u8,
+    /// Pages aren't cropped if less than this much of their width or height would be left, from 0 to 1
+    pub crop_min_content: f32,
+    /// Stitch each chapter's pages into one strip and re-slice it at gutters. Only done for long strip series when downloading.
+    pub stitch: bool,
+    /// Height slices of a stitched strip aim for, in pixels
+    pub slice_height: u32,
+    /// Whether pages read right to left, which decides the order of split spreads and which way they're rotated. Set from
+    /// the manga's original language when downloading.
+    pub right_to_left: bool,
+}
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            quality: 85,
+            max_height: None,
+            grayscale: false,
+            spreads: None,
+            crop: false,
+            crop_tolerance: 16,
+            crop_min_content: 0.5,
+            stitch: false,
+            slice_height: 2000,
+            right_to_left: true,
+        }
+    }
+}
+impl ProcessOptions {
+    // Whether pages need to be touched at all
+    pub fn is_active(&self) -> bool {
+        self.format.is_some() || self.max_height.is_some() || self.grayscale || self.spreads.is_some() || self.crop || self.stitch
+    }
+}
+
+/// Runs page processing on blocking threads, at most `workers` pages at a time, so that downloads carry on in the meantime.
+#[derive(Debug, Clone)]
+pub struct Processor {
+    options: Arc<ProcessOptions>,
+    permits: Arc<Semaphore>,
+}
+impl Processor {
+    pub fn new(options:ProcessOptions, workers:usize) -> Result<Self, ProcessError> {
+        if let Some(format) = options.format {
+            format.check_supported()?;
+        }
+
+        Ok(Self {
+            options: Arc::new(options),
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+        })
+    }
+
+    /// Processes the page at `path` in the background. The page is replaced by one or more files named `<path>.<n>.<ext>`,
+    /// which are returned in reading order.
+    pub fn spawn(&self, path:PathBuf) -> JoinHandle<Result<Vec<PathBuf>, ProcessError>> {
+        let options = self.options.clone();
+        let permits = self.permits.clone();
+
+        tokio::spawn(async move {
+            // The semaphore is never closed
+            let _permit = permits.acquire_owned().await.unwrap();
+            tokio::task::spawn_blocking(move || process_file(&path, &options)).await?
+        })
+    }
+
+    // Whether chapters are stitched into a strip, in which case pages are processed by [`Processor::stitch`] rather than one by one
+    pub fn stitches(&self) -> bool {
+        self.options.stitch
+    }
+
+    /// Stitches a chapter's pages into one strip in the background, and slices it into pages again at gutters, which are then
+    /// processed like any other page. The pages are replaced by files named `<first page>.<n>.<ext>`, which are returned in
+    /// reading order.
+    pub fn stitch(&self, pages:Vec<PathBuf>) -> JoinHandle<Result<Vec<PathBuf>, ProcessError>> {
+        let options = self.options.clone();
+        let permits = self.permits.clone();
+
+        tokio::spawn(async move {
+            let _permit = permits.acquire_owned().await.unwrap();
+            tokio::task::spawn_blocking(move || stitch_files(&pages, &options)).await?
+        })
+    }
+}
+
+fn process_file(path:&Path, options:&ProcessOptions) -> Result<Vec<PathBuf>, ProcessError> {
+    let data = fs::read(path)?;
+    let original_format = ::image::guess_format(&data).ok();
+    let image = match ::image::load_from_memory(&data) {
+        Ok(image) => image,
+        Err(e) => {
+            // Better an unprocessed page than a missing one. It's still moved out of the way of renumbered pages.
+            warn!("Leaving {} as is: {}", path.display(), e);
+            let extension = path.extension().unwrap_or_default().to_string_lossy();
+            let output = path.with_extension(format!("0.{}", extension));
+            fs::rename(path, &output)?;
+            return Ok(vec![output]);
+        },
+    };
+
+    let images = process_image(image, options);
+
+    let mut outputs = Vec::with_capacity(images.len());
+    for (n, image) in images.into_iter().enumerate() {
+        let (data, extension) = encode(&image, original_format, options)?;
+        let output = path.with_extension(format!("{}.{}", n, extension));
+        fs::write(&output, data)?;
+        outputs.push(output);
+    }
+
+    fs::remove_file(path)?;
+
+    Ok(outputs)
+}
+
+fn stitch_files(pages:&[PathBuf], options:&ProcessOptions) -> Result<Vec<PathBuf>, ProcessError> {
+    let first = match pages.first() {
+        Some(first) => first,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut images = Vec::with_capacity(pages.len());
+    let mut original_format = None;
+    for page in pages.iter() {
+        let data = fs::read(page)?;
+        original_format = original_format.or(::image::guess_format(&data).ok());
+        match ::image::load_from_memory(&data) {
+            Ok(image) => images.push(image),
+            Err(e) => {
+                // Stitching around a missing page would leave a gap, so each page is processed on its own instead
+                warn!("Not stitching {}: can't read {}: {}", first.parent().unwrap_or(first).display(), page.display(), e);
+                let mut outputs = Vec::new();
+                for page in pages.iter() {
+                    outputs.extend(process_file(page, options)?);
+                }
+
+                return Ok(outputs);
+            },
+        }
+    }
+
+    let strip = stitch(&images);
+    drop(images);
+
+    let mut outputs = Vec::new();
+    for slice in slice_strip(&strip, options.slice_height, options.crop_tolerance) {
+        for image in process_image(slice, options) {
+            let (data, extension) = encode(&image, original_format, options)?;
+            let output = first.with_extension(format!("{}.{}", outputs.len(), extension));
+            fs::write(&output, data)?;
+            outputs.push(output);
+        }
+    }
+
+    for page in pages.iter() {
+        fs::remove_file(page)?;
+    }
+
+    Ok(outputs)
+}
+
+// Narrower pages are centred on white
+fn stitch(images:&[DynamicImage]) -> DynamicImage {
+    let width = images.iter().map(|i| i.width()).max().unwrap_or(1);
+    let height = images.iter().map(|i| i.height()).sum::<u32>().max(1);
+    let mut strip = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+
+    let mut y = 0;
+    for image in images.iter() {
+        imageops::overlay(&mut strip, &image.to_rgb8(), ((width - image.width()) / 2) as i64, y as i64);
+        y += image.height();
+    }
+
+    DynamicImage::ImageRgb8(strip)
+}
+
+// Cuts the strip into slices of about `height`, at a row of one colour where there is one in the lower half of the slice
+fn slice_strip(strip:&DynamicImage, height:u32, tolerance:u8) -> Vec<DynamicImage> {
+    let luma = strip.to_luma8();
+    let height = height.max(1);
+    let width = luma.width() as usize;
+    let uniform = |y:u32| {
+        let row = &luma.as_raw()[y as usize * width..(y as usize + 1) * width];
+        let (min, max) = row.iter().fold((u8::MAX, u8::MIN), |(min, max), p| (min.min(*p), max.max(*p)));
+        max - min <= tolerance
+    };
+
+    let mut slices = Vec::new();
+    let mut top = 0;
+    while top < strip.height() {
+        // Saturating, as --slice-height can be anything
+        let end = top.saturating_add(height).min(strip.height());
+        let bottom = match end >= strip.height() {
+            true => strip.height(),
+            false => (top + height / 2..end).rev()
+                .find(|y| uniform(*y))
+                .unwrap_or(end),
+        };
+
+        // Never an empty slice
+        let bottom = bottom.max(top + 1);
+        slices.push(strip.crop_imm(0, top, strip.width(), bottom - top));
+        top = bottom;
+    }
+
+    slices
+}
+
+// Each step may turn one page into several
+fn process_image(mut image:DynamicImage, options:&ProcessOptions) -> Vec<DynamicImage> {
+    // Before splitting, as spreads have margins around the whole thing
+    if options.crop {
+        image = crop_borders(image, options.crop_tolerance, options.crop_min_content);
+    }
+
+    let images = match options.spreads {
+        Some(mode) if image.width() > image.height() => split_spread(image, mode, options.right_to_left),
+        _ => vec![image],
+    };
+
+    images.into_iter()
+        .map(|mut image| {
+            if options.grayscale {
+                image = image.grayscale();
+            }
+
+            if let Some(max_height) = options.max_height {
+                if image.height() > max_height {
+                    let width = ((image.width() as u64 * max_height as u64) / image.height() as u64).max(1) as u32;
+                    image = image.resize_exact(width, max_height, FilterType::Lanczos3);
+                }
+            }
+
+            image
+        })
+        .collect()
+}
+
+fn crop_borders(image:DynamicImage, tolerance:u8, min_content:f32) -> DynamicImage {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    let row = |y:u32, xs:std::ops::Range<u32>| xs.map(|x| luma.get_pixel(x, y)[0]).collect::<Vec<u8>>();
+    let column = |x:u32, ys:std::ops::Range<u32>| ys.map(|y| luma.get_pixel(x, y)[0]).collect::<Vec<u8>>();
+
+    let top = border_width((0..height).map(|y| row(y, 0..width)), tolerance);
+    // Blank pages have nothing to crop to
+    if top >= height {
+        return image;
+    }
+
+    // A white border can run straight into a black one, or with a high tolerance a row can count as both, leaving nothing
+    let bottom = border_width((0..height).rev().map(|y| row(y, 0..width)), tolerance);
+    let content_height = match height.checked_sub(top + bottom) {
+        Some(content_height) if content_height > 0 => content_height,
+        _ => return image,
+    };
+
+    let rows = top..top + content_height;
+    let left = border_width((0..width).map(|x| column(x, rows.clone())), tolerance);
+    let right = border_width((0..width).rev().map(|x| column(x, rows.clone())), tolerance);
+    let content_width = match width.checked_sub(left + right) {
+        Some(content_width) if content_width > 0 => content_width,
+        _ => return image,
+    };
+
+    let too_small = (content_width as f32) < width as f32 * min_content || (content_height as f32) < height as f32 * min_content;
+    if too_small || (content_width, content_height) == (width, height) {
+        return image;
+    }
+
+    image.crop_imm(left, top, content_width, content_height)
+}
+
+// Number of lines, from the edge in, that are the same colour as the outermost one, if that's white or black
+fn border_width(mut lines:impl Iterator<Item=Vec<u8>>, tolerance:u8) -> u32 {
+    let matches = |line:&[u8], colour:u8| {
+        let strays = line.iter().filter(|p| p.abs_diff(colour) > tolerance).count();
+        strays as f32 <= line.len() as f32 * CROP_NOISE
+    };
+
+    let colour = match lines.next() {
+        Some(line) if matches(&line, 255) => 255,
+        Some(line) if matches(&line, 0) => 0,
+        _ => return 0,
+    };
+
+    1 + lines.take_while(|line| matches(line, colour)).count() as u32
+}
+
+fn split_spread(image:DynamicImage, mode:SpreadMode, right_to_left:bool) -> Vec<DynamicImage> {
+    let (width, height) = (image.width(), image.height());
+    match mode {
+        SpreadMode::Split => {
+            let left = image.crop_imm(0, 0, width / 2, height);
+            let right = image.crop_imm(width / 2, 0, width - width / 2, height);
+            match right_to_left {
+                true => vec![right, left],
+                false => vec![left, right],
+            }
+        },
+        // Whichever half is read first ends up on top
+        SpreadMode::Rotate => match right_to_left {
+            true => vec![image.rotate270()],
+            false => vec![image.rotate90()],
+        },
+    }
+}
+
+fn encode(image:&DynamicImage, original_format:Option<ImageFormat>, options:&ProcessOptions) -> Result<(Vec<u8>, &'static str), ProcessError> {
+    let quality = options.quality.clamp(1, 100);
+    let mut data = Cursor::new(Vec::new());
+
+    let format = match (options.format, original_format) {
+        (Some(format), _) => format,
+        (None, Some(ImageFormat::Jpeg)) => ConvertFormat::JPEG,
+        // Everything else is kept lossless
+        (None, _) => {
+            image.write_to(&mut data, ImageOutputFormat::Png)?;
+            return Ok((data.into_inner(), "png"));
+        },
+    };
+
+    match format {
+        ConvertFormat::JPEG => image.write_to(&mut data, ImageOutputFormat::Jpeg(quality))?,
+        // The lossy encoder only takes RGB(A). It's deprecated in favour of image-rs' own lossless one, which makes
+        // far bigger pages.
+        #[allow(deprecated)]
+        ConvertFormat::WebP => match image.color().has_alpha() {
+            true => {
+                let rgba = image.to_rgba8();
+                WebPEncoder::new_with_quality(&mut data, WebPQuality::lossy(quality)).encode(rgba.as_raw(), rgba.width(), rgba.height(), ::image::ColorType::Rgba8)?;
+            },
+            false => {
+                let rgb = image.to_rgb8();
+                WebPEncoder::new_with_quality(&mut data, WebPQuality::lossy(quality)).encode(rgb.as_raw(), rgb.width(), rgb.height(), ::image::ColorType::Rgb8)?;
+            },
+        },
+        ConvertFormat::AVIF => encode_avif(image, quality, &mut data)?,
+        ConvertFormat::JXL => return Ok((encode_jxl(image, quality)?, format.extension())),
+    }
+
+    Ok((data.into_inner(), format.extension()))
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(image:&DynamicImage, quality:u8, data:&mut Cursor<Vec<u8>>) -> Result<(), ProcessError> {
+    use ::image::ImageEncoder;
+    use ::image::codecs::avif::AvifEncoder;
+
+    let rgba = image.to_rgba8();
+    // Speed 6 is a good deal faster than the default, for a little size
+    AvifEncoder::new_with_speed_quality(data, 6, quality).write_image(rgba.as_raw(), rgba.width(), rgba.height(), ::image::ColorType::Rgba8)?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_:&DynamicImage, _:u8, _:&mut Cursor<Vec<u8>>) -> Result<(), ProcessError> {
+    Err(ProcessError::Unsupported("AVIF", "avif"))
+}
+
+#[cfg(feature = "jxl")]
+fn encode_jxl(image:&DynamicImage, quality:u8) -> Result<Vec<u8>, ProcessError> {
+    // Same quality to butteraugli distance mapping as cjxl
+    let quality = quality as f32;
+    let distance = match quality >= 30.0 {
+        true => 0.1 + (100.0 - quality) * 0.09,
+        false => 6.4 + 2.5f32.powf((30.0 - quality) / 5.0) / 6.25,
+    };
+
+    let rgb = image.to_rgb8();
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .quality(distance)
+        .build()
+        .map_err(|e| ProcessError::JXL(e.to_string()))?;
+
+    encoder.encode::<u8>(rgb.as_raw(), rgb.width(), rgb.height())
+        .map_err(|e| ProcessError::JXL(e.to_string()))
+}
+
+#[cfg(not(feature = "jxl"))]
+fn encode_jxl(_:&DynamicImage, _:u8) -> Result<Vec<u8>, ProcessError> {
+    Err(ProcessError::Unsupported("JPEG XL", "jxl"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // White above, black below, with no content rows in between
+    fn two_tone(width:u32, height:u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, y| match y < height / 2 {
+            true => Rgb([255, 255, 255]),
+            false => Rgb([0, 0, 0]),
+        }))
+    }
+
+    #[test]
+    fn leaves_two_tone_pages_alone() {
+        for tolerance in [16, 128, 255] {
+            let cropped = crop_borders(two_tone(100, 100), tolerance, 0.5);
+            assert_eq!((cropped.width(), cropped.height()), (100, 100));
+        }
+    }
+
+    #[test]
+    fn crops_uniform_borders() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 100, |x, y| match (10..90).contains(&x) && (20..70).contains(&y) {
+            true => Rgb([(x * 2) as u8, (y * 2) as u8, 128]),
+            false => Rgb([255, 255, 255]),
+        }));
+        let cropped = crop_borders(image, 16, 0.5);
+        assert_eq!((cropped.width(), cropped.height()), (80, 50));
+    }
+
+    #[test]
+    fn slices_with_huge_heights() {
+        let strip = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 100, Rgb([255, 255, 255])));
+        let slices = slice_strip(&strip, u32::MAX, 16);
+        assert_eq!(slices.iter().map(|s| s.height()).collect::<Vec<u32>>(), [100]);
+
+        let slices = slice_strip(&strip, 40, 16);
+        assert_eq!(slices.iter().map(|s| s.height()).sum::<u32>(), 100);
+    }
+}
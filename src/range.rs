@@ -1,99 +1,597 @@
-use std::num::ParseFloatError;
+use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq)]
 pub enum RangeError {
-    #[error("invalid range string supplied")]
-    Invalid,
-    #[error("invalid number supplied: {0}")]
-    Parse(#[from] ParseFloatError),
+    #[error("empty range at column {0}")]
+    Empty(usize),
+    #[error("invalid number '{token}' at column {column}")]
+    Number { token: String, column: usize },
+    #[error("invalid range '{token}' at column {column}: {reason}")]
+    Invalid { token: String, column: usize, reason: &'static str },
 }
 
-fn parse_volume_chapter(s:&str) -> Result<Option<(f64, Option<f64>)>, ParseFloatError> {
-    let mut parts = s.split(':');
-    let volume = match parts.next() {
-        Some(part) => part.trim().parse::<f64>()?,
-        None => return Ok(None),
-    };
+// A single token of the range string, along with where it was found for error reporting
+#[derive(Debug, Copy, Clone)]
+struct Token<'a> {
+    s: &'a str,
+    column: usize,
+}
+impl<'a> Token<'a> {
+    fn new(s:&'a str, column:usize) -> Self {
+        // Keep the column pointing at the first non-whitespace character
+        let trimmed = s.trim_start();
+        Self {
+            s: trimmed.trim_end(),
+            column: column + (s.len() - trimmed.len()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.s.is_empty()
+    }
 
-    let chapter = parts.next()
-        .map(|part| part.trim().parse::<f64>())
-        .transpose()?;
+    fn strip_prefix(&self, prefix:&str) -> Option<Token<'a>> {
+        let s = self.s.strip_prefix(prefix)?;
+        Some(Token::new(s, self.column + prefix.len()))
+    }
+
+    fn split_once(&self, delimiter:char) -> Option<(Token<'a>, Token<'a>)> {
+        let (a, b) = self.s.split_once(delimiter)?;
+        Some((Token::new(a, self.column), Token::new(b, self.column + a.len() + delimiter.len_utf8())))
+    }
+
+    fn parse_number(&self) -> Result<f64, RangeError> {
+        self.s.parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| self.number_error())
+    }
 
-    Ok(Some((volume, chapter)))
+    fn number_error(&self) -> RangeError {
+        RangeError::Number { token: self.s.to_string(), column: self.column }
+    }
+
+    fn invalid(&self, reason:&'static str) -> RangeError {
+        RangeError::Invalid { token: self.s.to_string(), column: self.column, reason }
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Range {
-    pub volume_start: f64,
-    pub volume_end: f64,
-    pub chapter_start: Option<f64>,
-    pub chapter_end: Option<f64>,
+fn parse_chapter_bound(token:Token) -> Result<Option<f64>, RangeError> {
+    match token.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(token.strip_prefix("c").unwrap_or(token).parse_number()?)),
+    }
 }
-impl Range {
-    pub fn new(s:&str) -> Result<Self, RangeError> {
-        let mut parts = s.split("-");
-        let (volume_start, chapter_start) = parse_volume_chapter(parts.next()
-            .ok_or(RangeError::Invalid)?)?
-            .ok_or(RangeError::Invalid)?;
 
-        let (mut volume_end, mut chapter_end) = (volume_start, chapter_start);
-        if let Some(part) = parts.next() {
-            let (ev, ec) = parse_volume_chapter(part)?.ok_or(RangeError::Invalid)?;
-            volume_end = ev;
-            chapter_end = ec;
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bound {
+    pub volume: f64,
+    pub chapter: Option<f64>,
+}
+impl Bound {
+    fn parse(token:Token) -> Result<Option<Self>, RangeError> {
+        if token.is_empty() {
+            return Ok(None);
         }
 
-        Ok(Self {
-            volume_start,
-            volume_end,
-            chapter_start,
-            chapter_end,
-        })
+        if token.s.starts_with('c') {
+            return Err(token.invalid("volume and chapter-only bounds cannot be mixed"));
+        }
+
+        let (volume, chapter) = match token.split_once(':') {
+            Some((volume, chapter)) => (volume, Some(chapter)),
+            None => (token, None),
+        };
+
+        Ok(Some(Self {
+            volume: volume.parse_number()?,
+            chapter: chapter.map(|c| c.parse_number()).transpose()?,
+        }))
     }
+}
 
-    pub fn from_str(s:&str) -> Result<Vec<Self>, RangeError> {
-        s.split(",")
-            .map(|part| Self::new(part))
-            .collect::<Result<Vec<Self>, RangeError>>()
+impl fmt::Display for Bound {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self.chapter {
+            Some(chapter) => write!(f, "{}:{}", self.volume, chapter),
+            None => write!(f, "{}", self.volume),
+        }
     }
+}
 
-    pub fn in_range(&self, volume:&str, chapter:&str) -> bool {
-        let v = match volume.parse::<f64>() {
-            Ok(v) => v,
-            Err(_) => return false,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Selector {
+    // Volume range, optionally bounded by chapter. Either end may be left open.
+    Volumes { start: Option<Bound>, end: Option<Bound> },
+    // Chapter range that ignores volumes entirely. Either end may be left open.
+    Chapters { start: Option<f64>, end: Option<f64> },
+    // The N most recent chapters
+    Latest(usize),
+    // Chapters that haven't been assigned a volume
+    NoVolume,
+}
+impl Selector {
+    fn parse(token:Token) -> Result<Self, RangeError> {
+        if let Some(n) = token.strip_prefix("latest:") {
+            return match n.s.parse::<usize>() {
+                Ok(0) => Err(n.invalid("must select at least one chapter")),
+                Ok(n) => Ok(Selector::Latest(n)),
+                Err(_) => Err(n.number_error()),
+            };
+        }
+
+        if token.s == "nv" {
+            return Ok(Selector::NoVolume);
+        }
+
+        let (start, end) = match token.split_once('-') {
+            Some((start, end)) => {
+                if end.s.contains('-') {
+                    return Err(token.invalid("a range can only have one '-'"));
+                }
+
+                if start.is_empty() && end.is_empty() {
+                    return Err(token.invalid("a range needs at least one bound"));
+                }
+
+                (start, end)
+            },
+            None => (token, token),
         };
 
-        let c = match chapter.parse::<f64>() {
-            Ok(c) => c,
-            Err(_) => return false,
+        let chapter_only = match start.is_empty() {
+            true => end.s.starts_with('c'),
+            false => start.s.starts_with('c'),
         };
 
-        if self.volume_start > v || self.volume_end < v { // Inclusive
-            return false;
+        match chapter_only {
+            true => Ok(Selector::Chapters {
+                start: parse_chapter_bound(start)?,
+                end: parse_chapter_bound(end)?,
+            }),
+            false => Ok(Selector::Volumes {
+                start: Bound::parse(start)?,
+                end: Bound::parse(end)?,
+            }),
         }
+    }
 
-        if let Some(cs) = self.chapter_start {
-            if cs > c {
-                return false;
+    // recency is the number of (distinct) chapters newer than this one
+    fn in_range(&self, volume:&str, chapter:&str, recency:usize) -> bool {
+        let within = |start:Option<f64>, end:Option<f64>, n:&str| -> bool {
+            if start.is_none() && end.is_none() {
+                return true;
             }
-        }
 
-        if let Some(ce) = self.chapter_end {
-            if ce < c {
-                return false;
-            }
-        }
+            let n = match n.parse::<f64>() {
+                Ok(n) => n,
+                Err(_) => return false,
+            };
 
-        true
+            start.map(|s| s <= n).unwrap_or(true) && end.map(|e| n <= e).unwrap_or(true) // Inclusive
+        };
+
+        match self {
+            // Chapters without a volume aren't in any volume range; 'nv' or chapter-only ranges select them
+            Selector::Volumes { start, end } => self.in_volume_range(volume)
+                && within(start.and_then(|b| b.chapter), end.and_then(|b| b.chapter), chapter),
+            Selector::Chapters { start, end } => within(*start, *end, chapter),
+            Selector::Latest(n) => recency < *n,
+            Selector::NoVolume => volume.is_empty(),
+        }
     }
 
-    pub fn in_volume_range(&self, volume:&str) -> bool {
+    fn in_volume_range(&self, volume:&str) -> bool {
         let v = match volume.parse::<f64>() {
             Ok(v) => v,
             Err(_) => return false,
         };
 
-        self.volume_start <= v && v <= self.volume_end
+        match self {
+            Selector::Volumes { start, end } => start.map(|b| b.volume <= v).unwrap_or(true)
+                && end.map(|b| v <= b.volume).unwrap_or(true),
+            _ => false,
+        }
+    }
+
+    fn has_chapter_bounds(&self) -> bool {
+        match self {
+            Selector::Volumes { start, end } => start.and_then(|b| b.chapter).is_some() || end.and_then(|b| b.chapter).is_some(),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selector::Volumes { start, end } if start == end => match start {
+                Some(start) => write!(f, "{}", start),
+                None => write!(f, "-"),
+            },
+            Selector::Volumes { start, end } => {
+                if let Some(start) = start {
+                    write!(f, "{}", start)?;
+                }
+
+                write!(f, "-")?;
+
+                match end {
+                    Some(end) => write!(f, "{}", end),
+                    None => Ok(()),
+                }
+            },
+            Selector::Chapters { start, end } if start == end => match start {
+                Some(start) => write!(f, "c{}", start),
+                None => write!(f, "-"),
+            },
+            Selector::Chapters { start, end } => {
+                if let Some(start) = start {
+                    write!(f, "c{}", start)?;
+                }
+
+                write!(f, "-")?;
+
+                match end {
+                    Some(end) => write!(f, "c{}", end),
+                    None => Ok(()),
+                }
+            },
+            Selector::Latest(n) => write!(f, "latest:{}", n),
+            Selector::NoVolume => write!(f, "nv"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Range {
+    pub selector: Selector,
+    pub exclude: bool,
+}
+impl Range {
+    fn parse(token:Token) -> Result<Self, RangeError> {
+        if token.is_empty() {
+            return Err(RangeError::Empty(token.column));
+        }
+
+        match token.strip_prefix("!") {
+            Some(token) => Ok(Self {
+                selector: Selector::parse(token)?,
+                exclude: true,
+            }),
+            None => Ok(Self {
+                selector: Selector::parse(token)?,
+                exclude: false,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self.exclude {
+            true => write!(f, "!{}", self.selector),
+            false => write!(f, "{}", self.selector),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeSet {
+    pub ranges: Vec<Range>,
+}
+impl RangeSet {
+    // Chapters are selected if any non-excluding range matches them (or there are none), and no excluding range does.
+    pub fn in_range(&self, volume:&str, chapter:&str, recency:usize) -> bool {
+        let mut includes = self.ranges.iter().filter(|r| !r.exclude).peekable();
+        let included = includes.peek().is_none() || includes.any(|r| r.selector.in_range(volume, chapter, recency));
+
+        included && !self.ranges.iter()
+            .filter(|r| r.exclude)
+            .any(|r| r.selector.in_range(volume, chapter, recency))
+    }
+
+    // Only volume ranges can select whole volumes; excluding a volume only counts if it isn't narrowed down to chapters
+    pub fn in_volume_range(&self, volume:&str) -> bool {
+        let mut includes = self.ranges.iter().filter(|r| !r.exclude).peekable();
+        let included = includes.peek().is_none() || includes.any(|r| r.selector.in_volume_range(volume));
+
+        included && !self.ranges.iter()
+            .filter(|r| r.exclude && !r.selector.has_chapter_bounds())
+            .any(|r| r.selector.in_volume_range(volume))
+    }
+}
+impl fmt::Display for RangeSet {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let ranges = self.ranges.iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+
+        write!(f, "{}", ranges.join(","))
+    }
+}
+impl FromStr for RangeSet {
+    type Err = RangeError;
+
+    fn from_str(s:&str) -> Result<Self, Self::Err> {
+        let mut column = 1;
+        let mut ranges = vec![];
+        for part in s.split(',') {
+            ranges.push(Range::parse(Token::new(part, column))?);
+            column += part.len() + 1;
+        }
+
+        Ok(Self {
+            ranges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn parse(s:&str) -> RangeSet {
+        s.parse::<RangeSet>().unwrap()
+    }
+
+    fn volumes(start:Option<(f64, Option<f64>)>, end:Option<(f64, Option<f64>)>) -> Selector {
+        let bound = |b:Option<(f64, Option<f64>)>| b.map(|(volume, chapter)| Bound { volume, chapter });
+        Selector::Volumes { start: bound(start), end: bound(end) }
+    }
+
+    fn selectors(s:&str) -> Vec<(Selector, bool)> {
+        parse(s).ranges.iter()
+            .map(|r| (r.selector, r.exclude))
+            .collect()
+    }
+
+    #[test]
+    fn parses_single_volume() {
+        assert_eq!(selectors("3"), vec![(volumes(Some((3.0, None)), Some((3.0, None))), false)]);
+    }
+
+    #[test]
+    fn parses_single_chapter_of_volume() {
+        assert_eq!(selectors("3:12"), vec![(volumes(Some((3.0, Some(12.0))), Some((3.0, Some(12.0)))), false)]);
+    }
+
+    #[test]
+    fn parses_readme_example() {
+        assert_eq!(selectors("1,9:55-12:68"), vec![
+            (volumes(Some((1.0, None)), Some((1.0, None))), false),
+            (volumes(Some((9.0, Some(55.0))), Some((12.0, Some(68.0)))), false),
+        ]);
+    }
+
+    #[test]
+    fn parses_open_ends() {
+        assert_eq!(selectors("12-"), vec![(volumes(Some((12.0, None)), None), false)]);
+        assert_eq!(selectors("-3"), vec![(volumes(None, Some((3.0, None))), false)]);
+        assert_eq!(selectors("-3:20"), vec![(volumes(None, Some((3.0, Some(20.0)))), false)]);
+    }
+
+    #[test]
+    fn parses_chapter_only() {
+        assert_eq!(selectors("c100-c150"), vec![(Selector::Chapters { start: Some(100.0), end: Some(150.0) }, false)]);
+        assert_eq!(selectors("c100-150"), vec![(Selector::Chapters { start: Some(100.0), end: Some(150.0) }, false)]);
+        assert_eq!(selectors("c120-"), vec![(Selector::Chapters { start: Some(120.0), end: None }, false)]);
+        assert_eq!(selectors("-c5"), vec![(Selector::Chapters { start: None, end: Some(5.0) }, false)]);
+        assert_eq!(selectors("c7"), vec![(Selector::Chapters { start: Some(7.0), end: Some(7.0) }, false)]);
+    }
+
+    #[test]
+    fn parses_latest_no_volume_and_exclusions() {
+        assert_eq!(selectors("latest:5"), vec![(Selector::Latest(5), false)]);
+        assert_eq!(selectors("nv"), vec![(Selector::NoVolume, false)]);
+        assert_eq!(selectors("!3,!c5,!latest:1"), vec![
+            (volumes(Some((3.0, None)), Some((3.0, None))), true),
+            (Selector::Chapters { start: Some(5.0), end: Some(5.0) }, true),
+            (Selector::Latest(1), true),
+        ]);
+    }
+
+    #[test]
+    fn parses_fractional_chapters() {
+        assert_eq!(selectors("2:10.5"), vec![(volumes(Some((2.0, Some(10.5))), Some((2.0, Some(10.5)))), false)]);
+        assert_eq!(selectors("c10.5-c11.5"), vec![(Selector::Chapters { start: Some(10.5), end: Some(11.5) }, false)]);
+    }
+
+    #[test]
+    fn ignores_whitespace() {
+        assert_eq!(parse(" 1 - 3 : 4 ,  c5 - c6 , ! 2 , latest:3 "), parse("1-3:4,c5-c6,!2,latest:3"));
+    }
+
+    #[test]
+    fn reports_offending_token() {
+        assert_eq!("1,x".parse::<RangeSet>(), Err(RangeError::Number { token: String::from("x"), column: 3 }));
+        assert_eq!("1,2:y-3".parse::<RangeSet>(), Err(RangeError::Number { token: String::from("y"), column: 5 }));
+        assert_eq!("1,,2".parse::<RangeSet>(), Err(RangeError::Empty(3)));
+        assert_eq!("latest:x".parse::<RangeSet>(), Err(RangeError::Number { token: String::from("x"), column: 8 }));
+        assert!(matches!("latest:0".parse::<RangeSet>(), Err(RangeError::Invalid { column: 8, .. })));
+        assert!(matches!("1-2-3".parse::<RangeSet>(), Err(RangeError::Invalid { column: 1, .. })));
+        assert!(matches!("4,3-c5".parse::<RangeSet>(), Err(RangeError::Invalid { column: 5, .. })));
+        assert!(matches!(" -".parse::<RangeSet>(), Err(RangeError::Invalid { column: 2, .. })));
+        assert!("".parse::<RangeSet>().is_err());
+        assert!("inf".parse::<RangeSet>().is_err());
+        assert!("NaN".parse::<RangeSet>().is_err());
+    }
+
+    #[test]
+    fn matches_volume_ranges_inclusively() {
+        let r = parse("2-4");
+        assert!(!r.in_range("1", "1", 0));
+        assert!(r.in_range("2", "1", 0));
+        assert!(r.in_range("3", "20", 0));
+        assert!(r.in_range("4", "30", 0));
+        assert!(!r.in_range("4.5", "35", 0));
+        assert!(!r.in_range("", "10", 0));
+    }
+
+    #[test]
+    fn matches_chapter_bounds_within_volumes() {
+        let r = parse("9:55-12:68");
+        assert!(!r.in_range("9", "54", 0));
+        assert!(r.in_range("9", "55", 0));
+        assert!(r.in_range("12", "68", 0));
+        assert!(!r.in_range("12", "68.5", 0));
+        assert!(!r.in_range("13", "60", 0));
+    }
+
+    #[test]
+    fn matches_fractional_chapters() {
+        let r = parse("c10-c11");
+        assert!(r.in_range("", "10.5", 0));
+        assert!(r.in_range("1", "10.5", 0));
+        assert!(!r.in_range("1", "11.5", 0));
+        assert!(!r.in_range("1", "", 0));
+    }
+
+    #[test]
+    fn matches_volume_less_chapters_on_chapter_number() {
+        assert!(parse("c55-c68").in_range("", "60", 0));
+        assert!(!parse("c55-c68").in_range("", "70", 0));
+        assert!(parse("nv").in_range("", "60", 0));
+        assert!(!parse("nv").in_range("3", "60", 0));
+    }
+
+    #[test]
+    fn volume_ranges_skip_volume_less_chapters() {
+        assert!(!parse("3:12").in_range("", "12", 0));
+        assert!(parse("3:12").in_range("3", "12", 0));
+        assert!(!parse("9:55-12:68").in_range("", "60", 0));
+        assert!(!parse("9-12").in_range("", "60", 0));
+        assert!(parse("3:12,nv").in_range("", "40", 0));
+    }
+
+    #[test]
+    fn matches_latest() {
+        let r = parse("latest:2");
+        assert!(r.in_range("1", "1", 0));
+        assert!(r.in_range("1", "1", 1));
+        assert!(!r.in_range("1", "1", 2));
+    }
+
+    #[test]
+    fn matches_exclusions() {
+        let r = parse("!3");
+        assert!(r.in_range("2", "10", 0));
+        assert!(!r.in_range("3", "20", 0));
+
+        let r = parse("1-5,!3,!c41");
+        assert!(!r.in_range("6", "50", 0));
+        assert!(!r.in_range("3", "20", 0));
+        assert!(!r.in_range("4", "41", 0));
+        assert!(r.in_range("4", "42", 0));
+    }
+
+    #[test]
+    fn matches_volume_range() {
+        let r = parse("2-4,!3");
+        assert!(!r.in_volume_range("1"));
+        assert!(r.in_volume_range("2"));
+        assert!(!r.in_volume_range("3"));
+        assert!(r.in_volume_range("4"));
+        assert!(!r.in_volume_range(""));
+
+        // Only excluding part of a volume keeps its cover
+        assert!(parse("!3:4").in_volume_range("3"));
+        assert!(!parse("c5-c10").in_volume_range("1"));
+        assert!(parse("12-").in_volume_range("100"));
+    }
+
+    #[test]
+    fn displays_ranges() {
+        for s in ["1", "3:12", "1,9:55-12:68", "12-", "-3", "-3:20", "c100-c150", "c120-", "-c5", "c7", "latest:5", "nv", "!3,!c5,!latest:1", "2:10.5"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+
+        assert_eq!(parse(" 1 - 3 ").to_string(), "1-3");
+        assert_eq!(parse("3:4-3:4").to_string(), "3:4");
+    }
+
+    fn number() -> impl Strategy<Value = f64> {
+        prop_oneof![
+            (0u32..2000).prop_map(|n| n as f64),
+            (0u32..20000).prop_map(|n| n as f64 / 10.0),
+            0.0..1e6f64,
+        ]
+    }
+
+    fn bound() -> impl Strategy<Value = Bound> {
+        (number(), proptest::option::of(number())).prop_map(|(volume, chapter)| Bound { volume, chapter })
+    }
+
+    fn selector() -> impl Strategy<Value = Selector> {
+        prop_oneof![
+            (proptest::option::of(bound()), proptest::option::of(bound()))
+                .prop_filter("needs a bound", |(s, e)| s.is_some() || e.is_some())
+                .prop_map(|(start, end)| Selector::Volumes { start, end }),
+            (proptest::option::of(number()), proptest::option::of(number()))
+                .prop_filter("needs a bound", |(s, e)| s.is_some() || e.is_some())
+                .prop_map(|(start, end)| Selector::Chapters { start, end }),
+            (1usize..1000).prop_map(Selector::Latest),
+            Just(Selector::NoVolume),
+        ]
+    }
+
+    fn range_set() -> impl Strategy<Value = RangeSet> {
+        proptest::collection::vec((selector(), any::<bool>()), 1..8)
+            .prop_map(|ranges| RangeSet {
+                ranges: ranges.into_iter()
+                    .map(|(selector, exclude)| Range { selector, exclude })
+                    .collect(),
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_through_display(set in range_set()) {
+            prop_assert_eq!(set.to_string().parse::<RangeSet>(), Ok(set));
+        }
+
+        #[test]
+        fn whitespace_is_insignificant(set in range_set(), pad in "[ \t]{0,3}") {
+            let padded = set.to_string()
+                .split(',')
+                .map(|r| format!("{}{}{}", pad, r, pad))
+                .collect::<Vec<String>>()
+                .join(",");
+
+            prop_assert_eq!(padded.parse::<RangeSet>(), Ok(set));
+        }
+
+        #[test]
+        fn volume_ranges_are_inclusive(a in number(), b in number(), c in number()) {
+            let (start, end) = if a <= b { (a, b) } else { (b, a) };
+            let set = format!("{}-{}", start, end).parse::<RangeSet>().unwrap();
+
+            prop_assert!(set.in_range(&start.to_string(), &c.to_string(), 0));
+            prop_assert!(set.in_range(&end.to_string(), &c.to_string(), 0));
+            prop_assert!(set.in_volume_range(&start.to_string()));
+            prop_assert!(set.in_volume_range(&end.to_string()));
+            prop_assert_eq!(set.in_volume_range(&c.to_string()), start <= c && c <= end);
+        }
+
+        #[test]
+        fn chapter_ranges_are_inclusive(a in number(), b in number(), c in number(), volume in "[0-9]{0,2}") {
+            let (start, end) = if a <= b { (a, b) } else { (b, a) };
+            let set = format!("c{}-c{}", start, end).parse::<RangeSet>().unwrap();
+
+            prop_assert!(set.in_range(&volume, &start.to_string(), 0));
+            prop_assert!(set.in_range(&volume, &end.to_string(), 0));
+            prop_assert_eq!(set.in_range(&volume, &c.to_string(), 0), start <= c && c <= end);
+        }
+
+        #[test]
+        fn exclusion_inverts_selection(selector in selector(), volume in "[0-9]{0,2}", chapter in "[0-9]{1,3}(\\.5)?", recency in 0usize..2000) {
+            let include = RangeSet { ranges: vec![Range { selector, exclude: false }] };
+            let exclude = RangeSet { ranges: vec![Range { selector, exclude: true }] };
+
+            prop_assert_ne!(include.in_range(&volume, &chapter, recency), exclude.in_range(&volume, &chapter, recency));
+        }
     }
 }
@@ -30,6 +30,9 @@ pub enum RequesterError {
     UnexpectedResponse(String),
 }
 
+pub const DEFAULT_API_URL:&str = "https://api.mangadex.org";
+pub const DEFAULT_UPLOADS_URL:&str = "https://uploads.mangadex.org";
+
 fn get_host(url:&str) -> Option<String> {
     lazy_static! {
         static ref HOST_RE:Regex = Regex::new(r"https?://([^/]+)/?.*").unwrap();
@@ -58,6 +61,11 @@ pub struct RateLimitedRequester {
     client: Client,
     sources: HashMap<String, RequesterSource>,
 }
+impl Default for RateLimitedRequester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl RateLimitedRequester {
     pub fn new() -> Self {
         Self {
@@ -66,14 +74,14 @@ impl RateLimitedRequester {
         }
     }
 
-    pub fn new_with_defaults() -> Self {
+    pub fn new_with_base_urls(api_url:&str, uploads_url:&str) -> Result<Self, RequesterError> {
         let mut requester = Self::new();
         // These three sources have been confirmed not to throw errors.
-        let _ = requester.insert_source("main", "https://api.mangadex.org", Duration::from_millis(200)).unwrap();
-        let _ = requester.insert_source("cdn", "https://api.mangadex.org", Duration::from_millis(1500)).unwrap();
-        let _ = requester.insert_source("content", "https://uploads.mangadex.org", Duration::from_millis(200)).unwrap();
+        requester.insert_source("main", api_url, Duration::from_millis(200))?;
+        requester.insert_source("cdn", api_url, Duration::from_millis(1500))?;
+        requester.insert_source("content", uploads_url, Duration::from_millis(200))?;
 
-        requester
+        Ok(requester)
     }
 
     pub fn insert_source(&mut self, alias:&str, base_url:&str, timeout:Duration) -> Result<(), RequesterError> {
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+
+use clap::ValueEnum;
+use sha2::{ Digest, Sha256 };
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+// Characters Windows won't allow in names, on top of control characters
+const WINDOWS_RESERVED_CHARS:&[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES:&[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Which filesystems names have to be valid on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SanitiseMode {
+    /// Only strip what Linux and macOS can't store: slashes and control characters
+    Posix,
+    /// Also strip characters and names Windows (and most NAS shares) reject, and trailing dots
+    Windows,
+    /// Windows rules, and transliterate or replace everything outside ASCII
+    Ascii,
+}
+
+/// Turns titles and other metadata into names that are safe to use as files or folders.
+///
+/// Names are normalised to Unicode NFC, and shortened to `max_length` bytes with a hash of the full name appended, so that
+/// long names stay unique. Paths handed out through [`Sanitiser::claim`] are tracked, so two different things that sanitise to
+/// the same name don't overwrite one another. Clones share the claimed paths.
+#[derive(Debug, Clone)]
+pub struct Sanitiser {
+    pub mode: SanitiseMode,
+    /// Longest name allowed, in bytes
+    pub max_length: usize,
+    // Path -> whatever claimed it
+    claimed: Arc<Mutex<HashMap<String, String>>>,
+}
+impl Default for Sanitiser {
+    fn default() -> Self {
+        // Synology's encrypted shares cap names at 143 bytes, the lowest limit in common use
+        Self::new(SanitiseMode::Windows, 143)
+    }
+}
+impl Sanitiser {
+    pub fn new(mode:SanitiseMode, max_length:usize) -> Self {
+        Self {
+            mode,
+            // Room for at least one character and the hash suffix
+            max_length: max_length.max(16),
+            claimed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sanitises a single file or folder name.
+    pub fn sanitise(&self, name:&str) -> String {
+        self.sanitise_with_reserve(name, 0)
+    }
+
+    /// Sanitises a single name, leaving room for `reserve` more bytes, e.g. for an extension added later.
+    pub fn sanitise_with_reserve(&self, name:&str, reserve:usize) -> String {
+        let name = name.nfc().collect::<String>();
+
+        let name = match self.mode {
+            SanitiseMode::Posix => name.chars()
+                .filter(|c| *c != '/' && !c.is_control())
+                .collect::<String>(),
+            SanitiseMode::Windows => name.chars()
+                .filter(|c| !WINDOWS_RESERVED_CHARS.contains(c) && !c.is_control())
+                .collect::<String>(),
+            SanitiseMode::Ascii => name.nfkd()
+                .filter(|c| !is_combining_mark(*c))
+                .map(|c| if c.is_ascii() { c } else { '_' })
+                .filter(|c| !WINDOWS_RESERVED_CHARS.contains(c) && !c.is_control())
+                .collect::<String>(),
+        };
+
+        let name = match self.mode {
+            SanitiseMode::Posix => name.trim().to_string(),
+            // Windows silently drops trailing dots and spaces, so names ending in them can't be opened
+            SanitiseMode::Windows | SanitiseMode::Ascii => name.trim_start().trim_end_matches(|c:char| c == '.' || c.is_whitespace()).to_string(),
+        };
+
+        let name = match self.mode {
+            SanitiseMode::Posix => name,
+            SanitiseMode::Windows | SanitiseMode::Ascii => Self::avoid_reserved_name(name),
+        };
+
+        let name = match name.as_str() {
+            "" | "." | ".." => String::from("_"),
+            _ => name,
+        };
+
+        self.truncate(&name, self.max_length.saturating_sub(reserve).max(16))
+    }
+
+    /// Sanitises the last part of an already sanitised path again, leaving room for `reserve` more bytes.
+    pub fn fit_path(&self, path:&str, reserve:usize) -> String {
+        match path.rsplit_once('/') {
+            Some((parent, last)) => format!("{}/{}", parent, self.sanitise_with_reserve(last, reserve)),
+            None => self.sanitise_with_reserve(path, reserve),
+        }
+    }
+
+    // CON, NUL and friends are reserved with any extension, e.g. CON.txt
+    fn avoid_reserved_name(name:String) -> String {
+        let stem = name.split('.').next().unwrap_or_default();
+        match WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            true => format!("{}_{}", stem, &name[stem.len()..]),
+            false => name,
+        }
+    }
+
+    // Keeps the extension, and swaps the end of the stem for part of a hash of the full name
+    fn truncate(&self, name:&str, max_length:usize) -> String {
+        if name.len() <= max_length {
+            return name.to_string();
+        }
+
+        let extension = match name.rsplit_once('.') {
+            Some((_, extension)) if extension.len() <= 5 && extension.chars().all(|c| c.is_ascii_alphanumeric()) => &name[name.len() - extension.len() - 1..],
+            _ => "",
+        };
+
+        let hash = format!("~{}", &hex::encode(Sha256::digest(name.as_bytes()))[..8]);
+        let mut end = max_length.saturating_sub(hash.len() + extension.len());
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        format!("{}{}{}", name[..end].trim_end(), hash, extension)
+    }
+
+    // Windows and most NAS shares compare names case-insensitively
+    fn key(&self, path:&str) -> String {
+        match self.mode {
+            SanitiseMode::Posix => path.to_string(),
+            SanitiseMode::Windows | SanitiseMode::Ascii => path.to_lowercase(),
+        }
+    }
+
+    /// Claims `name` under the already claimed `prefix` for `owner`, e.g. a chapter's ID, and returns the name to use.
+    ///
+    /// The same owner always gets the same name back. If a different owner already has the name, " (2)", " (3)" and so on
+    /// are added to the last part of it, before any extension.
+    pub fn claim(&self, prefix:&str, name:&str, owner:&str) -> String {
+        let mut claimed = self.claimed.lock().unwrap_or_else(|e| e.into_inner());
+
+        let (parent, last) = match name.rsplit_once('/') {
+            Some((parent, last)) => (format!("{}/", parent), last),
+            None => (String::new(), name),
+        };
+
+        let (stem, extension) = match last.rsplit_once('.') {
+            // Chapter numbers such as 10.5 aren't extensions
+            Some((stem, extension)) if !stem.is_empty() && extension.len() <= 5 && extension.chars().all(|c| c.is_ascii_alphanumeric()) && extension.chars().any(|c| c.is_ascii_alphabetic()) => (stem, format!(".{}", extension)),
+            _ => (last, String::new()),
+        };
+
+        let mut n = 1;
+        loop {
+            let candidate = match n {
+                1 => name.to_string(),
+                n => format!("{}{}{}", parent, self.sanitise_with_reserve(&format!("{} ({})", stem, n), extension.len()), extension),
+            };
+
+            let key = match prefix.is_empty() {
+                true => self.key(&candidate),
+                false => self.key(&format!("{}/{}", prefix, candidate)),
+            };
+
+            match claimed.get(&key) {
+                Some(o) if o != owner => n += 1,
+                Some(_) => return candidate,
+                None => {
+                    claimed.insert(key, owner.to_string());
+                    return candidate;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows() -> Sanitiser {
+        Sanitiser::new(SanitiseMode::Windows, 143)
+    }
+
+    #[test]
+    fn strips_reserved_characters() {
+        assert_eq!(windows().sanitise("What? A <Manga>: \"Part\" 1/2*"), "What A Manga Part 12");
+        assert_eq!(Sanitiser::new(SanitiseMode::Posix, 143).sanitise("What? A: 1/2\u{7}"), "What? A: 12");
+    }
+
+    #[test]
+    fn strips_trailing_dots_and_spaces() {
+        assert_eq!(windows().sanitise("  Wait... "), "Wait");
+        assert_eq!(windows().sanitise(".."), "_");
+        assert_eq!(windows().sanitise("?"), "_");
+    }
+
+    #[test]
+    fn avoids_reserved_names() {
+        assert_eq!(windows().sanitise("con"), "con_");
+        assert_eq!(windows().sanitise("NUL.txt"), "NUL_.txt");
+        assert_eq!(windows().sanitise("Console"), "Console");
+        assert_eq!(Sanitiser::new(SanitiseMode::Posix, 143).sanitise("NUL"), "NUL");
+    }
+
+    #[test]
+    fn normalises_unicode() {
+        // e followed by a combining acute accent
+        assert_eq!(windows().sanitise("Caf\u{65}\u{301}"), "Caf\u{e9}");
+        assert_eq!(Sanitiser::new(SanitiseMode::Ascii, 143).sanitise("Café ふらいんぐうぃっち"), "Cafe _________");
+    }
+
+    #[test]
+    fn truncates_with_hash() {
+        let sanitiser = Sanitiser::new(SanitiseMode::Windows, 32);
+        let a = sanitiser.sanitise(&format!("{}a.png", "x".repeat(40)));
+        let b = sanitiser.sanitise(&format!("{}b.png", "x".repeat(40)));
+        assert_eq!(a.len(), 32);
+        assert!(a.ends_with(".png"));
+        assert_ne!(a, b);
+
+        // Never splits a character
+        let c = sanitiser.sanitise(&"ふ".repeat(20));
+        assert!(c.len() <= 32);
+    }
+
+    #[test]
+    fn disambiguates_collisions() {
+        let sanitiser = windows();
+        assert_eq!(sanitiser.claim("", "Volume 1/Chapter 1", "a"), "Volume 1/Chapter 1");
+        assert_eq!(sanitiser.claim("", "volume 1/chapter 1", "b"), "volume 1/chapter 1 (2)");
+        assert_eq!(sanitiser.claim("", "Volume 1/Chapter 1", "a"), "Volume 1/Chapter 1");
+        assert_eq!(sanitiser.claim("Chapter 1", "1.png", "p1"), "1.png");
+        assert_eq!(sanitiser.claim("Chapter 1", "1.png", "p2"), "1 (2).png");
+        assert_eq!(sanitiser.claim("", "Chapter 10.5", "c"), "Chapter 10.5");
+        assert_eq!(sanitiser.claim("", "Chapter 10.5", "d"), "Chapter 10.5 (2)");
+    }
+}
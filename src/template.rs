@@ -0,0 +1,313 @@
+use crate::chapter::ChapterMetadata;
+use crate::manga::MangaMetadata;
+use crate::sanitise::Sanitiser;
+use crate::utils;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("unclosed '{{' at column {0}")]
+    Unclosed(usize),
+    #[error("unmatched '}}' at column {0}")]
+    Unmatched(usize),
+    #[error("unknown variable '{name}' at column {column}")]
+    UnknownVariable { name: String, column: usize },
+    #[error("invalid width '{width}' at column {column}")]
+    Width { width: String, column: usize },
+    #[error("variable '{name}' at column {column} isn't available in {kind} templates")]
+    Unavailable { name: String, column: usize, kind: TemplateKind },
+    #[error("template is empty")]
+    Empty,
+}
+
+pub type Variables = HashMap<&'static str, String>;
+
+const SERIES_VARIABLES:&[&str] = &["title", "id", "language", "year", "status", "author", "artist"];
+const CHAPTER_VARIABLES:&[&str] = &["volume", "chapter", "chapter_title", "group", "chapter_id", "published"];
+const PAGE_VARIABLES:&[&str] = &["page", "pages", "ext"];
+const COVER_VARIABLES:&[&str] = &["volume", "ext"];
+const METADATA_VARIABLES:&[&str] = &["ext"];
+
+/// What a template names, which determines the variables available to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TemplateKind {
+    /// Series folder, relative to the output directory
+    Series,
+    /// Chapter folder, relative to the series folder
+    Chapter,
+    /// Page file, relative to the chapter folder
+    Page,
+    /// Volume cover file, relative to the series folder
+    Cover,
+    /// Metadata file, relative to the series folder
+    Metadata,
+}
+impl TemplateKind {
+    pub fn variables(&self) -> Vec<&'static str> {
+        let extra:&[&[&str]] = match self {
+            TemplateKind::Series => &[],
+            TemplateKind::Chapter => &[CHAPTER_VARIABLES],
+            TemplateKind::Page => &[CHAPTER_VARIABLES, PAGE_VARIABLES],
+            TemplateKind::Cover => &[COVER_VARIABLES],
+            TemplateKind::Metadata => &[METADATA_VARIABLES],
+        };
+
+        SERIES_VARIABLES.iter()
+            .chain(extra.iter().flat_map(|vs| vs.iter()))
+            .copied()
+            .collect()
+    }
+}
+
+impl fmt::Display for TemplateKind {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TemplateKind::Series => "series",
+            TemplateKind::Chapter => "chapter",
+            TemplateKind::Page => "page",
+            TemplateKind::Cover => "cover",
+            TemplateKind::Metadata => "metadata",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    // Numbers are zero padded to width; anything else is left as is
+    Variable { name: String, width: Option<usize>, column: usize },
+    Separator,
+}
+
+/// A path template, e.g. `Vol.{volume:02}/Ch.{chapter:04} [{group}]`.
+///
+/// `{name}` is replaced with the variable's value, and `{name:N}` zero pads numbers to `N` digits. `{{` and `}}` are literal
+/// braces. Values are sanitised so they can't add folders of their own; only `/` in the template itself does. Any folder whose
+/// variables are all empty, such as `Vol.{volume}` for a chapter without a volume, is left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+    source: String,
+}
+impl Template {
+    // Checks that every variable used is available to the kind of template
+    pub fn parse(s:&str, kind:TemplateKind) -> Result<Self, TemplateError> {
+        let template = s.parse::<Self>()?;
+        let available = kind.variables();
+
+        for segment in template.segments.iter() {
+            if let Segment::Variable { name, column, .. } = segment {
+                if !available.contains(&name.as_str()) {
+                    return Err(TemplateError::Unavailable { name: name.clone(), column: *column, kind });
+                }
+            }
+        }
+
+        Ok(template)
+    }
+
+    pub fn uses(&self, variable:&str) -> bool {
+        self.segments.iter().any(|s| matches!(s, Segment::Variable { name, .. } if name == variable))
+    }
+
+    pub fn render(&self, variables:&Variables, sanitiser:&Sanitiser) -> String {
+        let mut components = Vec::new();
+        let mut component = String::new();
+        // Whether the current folder has variables, and whether any of them were non-empty
+        let (mut has_variables, mut has_values) = (false, false);
+
+        for segment in self.segments.iter().chain(std::iter::once(&Segment::Separator)) {
+            match segment {
+                Segment::Literal(s) => component.push_str(s),
+                Segment::Variable { name, width, .. } => {
+                    let value = variables.get(name.as_str()).map(|v| v.as_str()).unwrap_or_default();
+                    let value = match (width, value.parse::<f64>()) {
+                        (Some(width), Ok(n)) => utils::pad_number(n, *width),
+                        _ => value.to_string(),
+                    };
+
+                    has_variables = true;
+                    has_values |= !value.is_empty();
+                    component.push_str(&sanitiser.sanitise(&value));
+                },
+                Segment::Separator => {
+                    if !component.is_empty() && (!has_variables || has_values) {
+                        components.push(sanitiser.sanitise(&component));
+                    }
+
+                    component.clear();
+                    has_variables = false;
+                    has_values = false;
+                },
+            }
+        }
+
+        components.join("/")
+    }
+
+    pub fn series_variables(manga:&MangaMetadata, title:&str, language:&str) -> Variables {
+        let mut variables = Variables::new();
+        variables.insert("title", title.to_string());
+        variables.insert("id", manga.id.clone());
+        variables.insert("language", language.to_string());
+        variables.insert("year", manga.year.map(|y| y.to_string()).unwrap_or_default());
+        variables.insert("status", manga.status.map(|s| s.as_str().to_string()).unwrap_or_default());
+        variables.insert("author", manga.authors.join(", "));
+        variables.insert("artist", manga.artists.join(", "));
+
+        variables
+    }
+
+    pub fn chapter_variables(chapter:&ChapterMetadata) -> Variables {
+        let mut variables = Variables::new();
+        variables.insert("volume", chapter.volume.clone());
+        variables.insert("chapter", chapter.chapter.clone());
+        variables.insert("chapter_title", chapter.title.clone());
+        variables.insert("group", chapter.group.clone());
+        variables.insert("chapter_id", chapter.id.clone());
+        variables.insert("published", chapter.get_publish_date());
+
+        variables
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s:&str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err(TemplateError::Empty);
+        }
+
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '}' => return Err(TemplateError::Unmatched(i)),
+                '{' => {
+                    let end = s[i..].find('}').map(|e| i + e).ok_or(TemplateError::Unclosed(i))?;
+                    let inner = &s[i + 1..end];
+                    let (name, width) = match inner.split_once(':') {
+                        Some((name, width)) => (name, Some(width)),
+                        None => (inner, None),
+                    };
+
+                    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(TemplateError::UnknownVariable { name: name.to_string(), column: i });
+                    }
+
+                    let all = [SERIES_VARIABLES, CHAPTER_VARIABLES, PAGE_VARIABLES, COVER_VARIABLES, METADATA_VARIABLES];
+                    if !all.iter().any(|vs| vs.contains(&name)) {
+                        return Err(TemplateError::UnknownVariable { name: name.to_string(), column: i });
+                    }
+
+                    let width = width.map(|w| w.parse::<usize>().map_err(|_| TemplateError::Width { width: w.to_string(), column: i + 1 + name.len() + 1 }))
+                        .transpose()?;
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    segments.push(Segment::Variable { name: name.to_string(), width, column: i });
+                    while chars.peek().map(|(j, _)| *j <= end).unwrap_or(false) {
+                        chars.next();
+                    }
+                },
+                '/' | '\\' => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    segments.push(Segment::Separator);
+                },
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self {
+            segments,
+            source: s.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// User-supplied templates, which override the layout preset's naming where given.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    pub series: Option<Template>,
+    pub chapter: Option<Template>,
+    pub page: Option<Template>,
+    pub cover: Option<Template>,
+    pub metadata: Option<Template>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs:&[(&'static str, &str)]) -> Variables {
+        pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+    }
+
+    #[test]
+    fn renders_padded_numbers() {
+        let template = Template::parse("Vol.{volume:02}/Ch.{chapter:04} [{group}]", TemplateKind::Chapter).unwrap();
+        let rendered = template.render(&variables(&[("volume", "3"), ("chapter", "10.5"), ("group", "Some Group")]), &Sanitiser::default());
+        assert_eq!(rendered, "Vol.03/Ch.0010.5 [Some Group]");
+    }
+
+    #[test]
+    fn drops_folders_with_only_empty_variables() {
+        let template = Template::parse("Vol.{volume}/Ch.{chapter}", TemplateKind::Chapter).unwrap();
+        assert_eq!(template.render(&variables(&[("volume", ""), ("chapter", "7")]), &Sanitiser::default()), "Ch.7");
+    }
+
+    #[test]
+    fn escapes_values() {
+        let template = Template::parse("{title}", TemplateKind::Series).unwrap();
+        assert_eq!(template.render(&variables(&[("title", "A/B: C")]), &Sanitiser::default()), "AB C");
+    }
+
+    #[test]
+    fn literal_braces() {
+        let template = Template::parse("{{{page}}}", TemplateKind::Page).unwrap();
+        assert_eq!(template.render(&variables(&[("page", "1")]), &Sanitiser::default()), "{1}");
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert_eq!(Template::parse("{title", TemplateKind::Series), Err(TemplateError::Unclosed(0)));
+        assert_eq!(Template::parse("title}", TemplateKind::Series), Err(TemplateError::Unmatched(5)));
+        assert_eq!(Template::parse("{nope}", TemplateKind::Series), Err(TemplateError::UnknownVariable { name: String::from("nope"), column: 0 }));
+        assert_eq!(Template::parse("{page:x}", TemplateKind::Page), Err(TemplateError::Width { width: String::from("x"), column: 6 }));
+        assert_eq!(Template::parse("{page}", TemplateKind::Chapter), Err(TemplateError::Unavailable { name: String::from("page"), column: 0, kind: TemplateKind::Chapter }));
+        assert_eq!(Template::parse(" ", TemplateKind::Series), Err(TemplateError::Empty));
+    }
+}
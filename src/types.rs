@@ -1,102 +1,370 @@
 use serde::{ Deserialize, Serialize };
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Serialize)]
+// Localised strings, keyed by ISO-639 language code
+pub type LocalisedString = HashMap<String, String>;
+
+// Relationships that can be expanded in place with includes[]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Include {
+    Manga,
+    CoverArt,
+    Author,
+    Artist,
+    ScanlationGroup,
+    User,
+}
+impl Include {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Include::Manga => "manga",
+            Include::CoverArt => "cover_art",
+            Include::Author => "author",
+            Include::Artist => "artist",
+            Include::ScanlationGroup => "scanlation_group",
+            Include::User => "user",
+        }
+    }
+
+    pub fn to_query(includes:&[Include]) -> String {
+        includes.iter()
+            .map(|i| format!("&includes[]={}", i.as_str()))
+            .collect::<String>()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum MangaStatus {
+    Ongoing,
+    Completed,
+    Hiatus,
+    Cancelled,
+    #[serde(other)]
+    Unknown,
+}
+impl MangaStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MangaStatus::Ongoing => "ongoing",
+            MangaStatus::Completed => "completed",
+            MangaStatus::Hiatus => "hiatus",
+            MangaStatus::Cancelled => "cancelled",
+            MangaStatus::Unknown => "unknown",
+        }
+    }
+}
+
+// Content rating as MangaDex sends it, which may be one the CLI doesn't know about yet
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum RawContentRating {
+    Safe,
+    Suggestive,
+    Erotica,
+    Pornographic,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum Demographic {
+    Shounen,
+    Shoujo,
+    Josei,
+    Seinen,
+    #[serde(other)]
+    Unknown,
+}
+impl Demographic {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Demographic::Shounen => "shounen",
+            Demographic::Shoujo => "shoujo",
+            Demographic::Josei => "josei",
+            Demographic::Seinen => "seinen",
+            Demographic::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
 pub struct TagDataAttributes {
-    pub name: HashMap<String, String>,
+    pub name: LocalisedString,
+    #[serde(default)]
+    pub description: LocalisedString,
+    pub group: Option<String>,
+    pub version: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TagData {
+    #[serde(default)]
+    pub id: String,
     pub attributes: TagDataAttributes,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
 pub struct MangaDataAttributes {
-    pub title: HashMap<String, String>,
-    #[serde(rename="altTitles")]
-    pub alt_titles: Vec<HashMap<String, String>>,
-    #[serde(rename="availableTranslatedLanguages")]
+    pub title: LocalisedString,
+    #[serde(default)]
+    pub alt_titles: Vec<LocalisedString>,
+    #[serde(rename="availableTranslatedLanguages", default)]
     pub available_languages: Vec<Option<String>>,
-    pub description: HashMap<String, String>,
+    #[serde(default)]
+    pub description: LocalisedString,
+    #[serde(default)]
     pub tags: Vec<TagData>,
+    pub is_locked: Option<bool>,
+    // Keyed by site, e.g. al (AniList), mal (MyAnimeList), mu (MangaUpdates), raw, engtl
+    pub links: Option<HashMap<String, String>>,
+    pub original_language: Option<String>,
+    pub last_volume: Option<String>,
+    pub last_chapter: Option<String>,
+    pub publication_demographic: Option<Demographic>,
+    pub status: Option<MangaStatus>,
+    pub year: Option<u64>,
+    pub content_rating: Option<RawContentRating>,
+    pub chapter_numbers_reset_on_new_volume: Option<bool>,
+    pub latest_uploaded_chapter: Option<String>,
+    pub state: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub version: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MangaData {
-    pub attributes: MangaDataAttributes,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct MangaDataResponse {
-    pub data: MangaData,
-}
-
-// We only care about 'scanlation_group' attributes
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RawChapterRelationshipAttributes {
-    pub name: Option<String>,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RawChapterRelationship {
-    #[serde(rename="type")]
+    #[serde(default)]
     pub id: String,
-    pub attributes: Option<RawChapterRelationshipAttributes>,
+    pub attributes: MangaDataAttributes,
+    #[serde(default)]
+    pub relationships: Vec<Relationship>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
 pub struct ChapterAttributes {
     pub volume: Option<String>,
     pub chapter: Option<String>,
+    pub title: Option<String>,
     #[serde(rename="translatedLanguage")]
     pub language: Option<String>,
+    pub external_url: Option<String>,
+    pub publish_at: Option<String>,
+    pub readable_at: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    #[serde(default)]
+    pub pages: u64,
+    #[serde(default)]
+    pub version: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChapterData {
     pub id: String,
     pub attributes: ChapterAttributes,
-    pub relationships: Vec<RawChapterRelationship>,
+    #[serde(default)]
+    pub relationships: Vec<Relationship>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct CoverArtAttributes {
+    pub volume: Option<String>,
+    pub file_name: String,
+    pub description: Option<String>,
+    pub locale: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoverArtData {
+    #[serde(default)]
+    pub id: String,
+    pub attributes: CoverArtAttributes,
+    #[serde(default)]
+    pub relationships: Vec<Relationship>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ChapterDataResponse {
-    pub data: Vec<ChapterData>,
+// Shared by authors and artists
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct AuthorAttributes {
+    pub name: String,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub biography: LocalisedString,
+    pub twitter: Option<String>,
+    pub pixiv: Option<String>,
+    pub melon_book: Option<String>,
+    pub fan_box: Option<String>,
+    pub booth: Option<String>,
+    pub nico_video: Option<String>,
+    pub skeb: Option<String>,
+    pub fantia: Option<String>,
+    pub tumblr: Option<String>,
+    pub youtube: Option<String>,
+    pub weibo: Option<String>,
+    pub naver: Option<String>,
+    pub website: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthorData {
+    pub id: String,
+    pub attributes: AuthorAttributes,
+    #[serde(default)]
+    pub relationships: Vec<Relationship>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ScanlationGroupAttributes {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub alt_names: Vec<LocalisedString>,
+    pub website: Option<String>,
+    pub irc_server: Option<String>,
+    pub irc_channel: Option<String>,
+    pub discord: Option<String>,
+    pub contact_email: Option<String>,
+    pub description: Option<String>,
+    pub twitter: Option<String>,
+    pub manga_updates: Option<String>,
+    pub focused_languages: Option<Vec<String>>,
+    pub locked: Option<bool>,
+    pub official: Option<bool>,
+    pub inactive: Option<bool>,
+    pub verified: Option<bool>,
+    pub publish_delay: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScanlationGroupData {
+    pub id: String,
+    pub attributes: ScanlationGroupAttributes,
+    #[serde(default)]
+    pub relationships: Vec<Relationship>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserAttributes {
+    pub username: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub version: Option<u64>,
+}
+
+// Related entities. Attributes are only present if the relationship was expanded with includes[].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag="type", rename_all="snake_case")]
+pub enum Relationship {
+    Manga {
+        id: String,
+        // How the related manga relates to this one, e.g. sequel
+        related: Option<String>,
+        attributes: Option<Box<MangaDataAttributes>>,
+    },
+    Chapter {
+        id: String,
+        attributes: Option<ChapterAttributes>,
+    },
+    CoverArt {
+        id: String,
+        attributes: Option<CoverArtAttributes>,
+    },
+    Author {
+        id: String,
+        attributes: Option<AuthorAttributes>,
+    },
+    Artist {
+        id: String,
+        attributes: Option<AuthorAttributes>,
+    },
+    ScanlationGroup {
+        id: String,
+        attributes: Option<ScanlationGroupAttributes>,
+    },
+    User {
+        id: String,
+        attributes: Option<UserAttributes>,
+    },
+    Leader {
+        id: String,
+        attributes: Option<UserAttributes>,
+    },
+    Member {
+        id: String,
+        attributes: Option<UserAttributes>,
+    },
+    Creator {
+        id: String,
+        attributes: Option<UserAttributes>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+impl Relationship {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Relationship::Manga { id, .. }
+                | Relationship::Chapter { id, .. }
+                | Relationship::CoverArt { id, .. }
+                | Relationship::Author { id, .. }
+                | Relationship::Artist { id, .. }
+                | Relationship::ScanlationGroup { id, .. }
+                | Relationship::User { id, .. }
+                | Relationship::Leader { id, .. }
+                | Relationship::Member { id, .. }
+                | Relationship::Creator { id, .. } => Some(id),
+            Relationship::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EntityResponse<T> {
+    pub data: T,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CollectionResponse<T> {
+    pub data: Vec<T>,
     pub limit: u64,
     pub offset: u64,
     pub total: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+pub type MangaDataResponse = EntityResponse<MangaData>;
+pub type ChapterDataResponse = CollectionResponse<ChapterData>;
+pub type CoverArtResponse = CollectionResponse<CoverArtData>;
+pub type AuthorResponse = EntityResponse<AuthorData>;
+pub type ScanlationGroupResponse = EntityResponse<ScanlationGroupData>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChapterImageData {
     pub hash: String,
     pub data: Vec<String>,
+    #[serde(rename="dataSaver", default)]
+    pub data_saver: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChapterImageResponse {
     #[serde(rename="baseUrl")]
     pub base_url: String,
     pub chapter: ChapterImageData,
 }
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct CoverArtAttributes {
-    pub volume: Option<String>,
-    #[serde(rename="fileName")]
-    pub file_name: String,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct CoverArtData {
-    pub attributes: CoverArtAttributes,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct CoverArtResponse {
-    pub data: Vec<CoverArtData>,
-    pub limit: u64,
-    pub offset: u64,
-    pub total: u64,
-}
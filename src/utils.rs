@@ -11,15 +11,16 @@ pub fn get_id(url:&str) -> Option<String> {
     Some(id)
 }
 
-pub fn escape_path(path:&str) -> String {
-    lazy_static! {
-        static ref RESERVED_RE:Regex = Regex::new(r"[\\/:|&<>]").unwrap();
+// Zero pads the integer part only, e.g. 5.5 -> 005.5
+pub fn pad_number(n:f64, width:usize) -> String {
+    let s = n.to_string();
+    match s.split_once('.') {
+        Some((integer, fraction)) => format!("{:0>width$}.{}", integer, fraction, width=width),
+        None => format!("{:0>width$}", s, width=width),
     }
-
-    RESERVED_RE.replace_all(path, "").to_string()
 }
 
 pub fn get_retry_after(res:&Response) -> Option<DateTime<Utc>> {
-    let timestamp = i64::from_str_radix(res.headers().get("X-RateLimit-Retry-After")?.to_str().ok()?, 10).ok()?;
+    let timestamp = res.headers().get("X-RateLimit-Retry-After")?.to_str().ok()?.parse::<i64>().ok()?;
     Some(Utc.from_utc_datetime(&NaiveDateTime::from_timestamp_opt(timestamp, 0)?))
 }
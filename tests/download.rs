@@ -0,0 +1,793 @@
+mod mock;
+
+use mangadex_downloader::{ download, resolve, verify, API, APIError, ContentRating, ConvertFormat, DownloadError, DownloadOptions, FeedFilter, LayoutPreset, MetadataOutputFormat, Padding, ProblemKind, ProcessOptions, SpreadMode, Template, TemplateKind, Templates, TLSelectionStrategy };
+use mangadex_downloader::chapter::ImageDownloadError;
+use mock::{ ChapterFixture, MockMangaDex, MANGA_ID, TITLE };
+
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use chrono::{ TimeZone, Utc };
+use tempfile::TempDir;
+
+fn api(mock:&MockMangaDex) -> API {
+    API::builder()
+        .api_url(&mock.uri())
+        .uploads_url(&mock.uri())
+        .build()
+        .unwrap()
+}
+
+fn options(output_dir:&Path) -> DownloadOptions {
+    DownloadOptions {
+        output_dir: output_dir.to_path_buf(),
+        quiet: true,
+        ..DownloadOptions::default()
+    }
+}
+
+fn series_dir(output_dir:&Path) -> PathBuf {
+    output_dir.join(TITLE)
+}
+
+fn feed_filter() -> FeedFilter {
+    FeedFilter {
+        languages: vec![String::from("en")],
+        excluded_groups: vec![],
+        content_ratings: vec![ContentRating::Safe],
+    }
+}
+
+async fn mount_series(mock:&MockMangaDex, chapters:&[ChapterFixture], covers:&[&str]) {
+    mock.mount_manga().await;
+    mock.mount_feed(chapters).await;
+    mock.mount_covers(covers).await;
+    for chapter in chapters.iter() {
+        mock.mount_chapter(chapter).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_whole_series() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 2),
+        ChapterFixture::new(2, Some("1"), Some("2"), 1),
+        ChapterFixture::new(3, None, Some("3"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let report = download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+
+    let series = series_dir(output.path());
+    assert_eq!(report.directory, series);
+    assert_eq!(report.chapters.len(), 3);
+    assert_eq!(report.chapters[0].pages, vec![series.join("Volume 1/Chapter 1/1.png"), series.join("Volume 1/Chapter 1/2.png")]);
+    assert_eq!(report.covers.len(), 1);
+    assert_eq!(report.metadata_file, Some(series.join("metadata.toml")));
+
+    assert_eq!(fs::read(series.join("Volume 1/Chapter 1/1.png")).unwrap(), chapters[0].pages[0]);
+    assert_eq!(fs::read(series.join("Volume 1/Chapter 1/2.png")).unwrap(), chapters[0].pages[1]);
+    assert_eq!(fs::read(series.join("Volume 1/Chapter 2/1.png")).unwrap(), chapters[1].pages[0]);
+    assert_eq!(fs::read(series.join("Chapter 3/1.png")).unwrap(), chapters[2].pages[0]);
+    assert!(series.join("Volume 1/cover.jpg").is_file());
+    // No partial downloads left over
+    assert_eq!(fs::read_dir(series.join("Volume 1/Chapter 1")).unwrap().count(), 2);
+    assert_eq!(fs::read_dir(series.join("Volume 1")).unwrap().count(), 3);
+
+    let metadata = fs::read_to_string(series.join("metadata.toml")).unwrap();
+    assert!(metadata.contains(TITLE));
+    assert!(metadata.contains("Tesuto Manga"));
+
+    // Language filtering happens server-side
+    let feed_requests = mock.requests_to(&format!("/manga/{}/feed", MANGA_ID)).await;
+    assert!(feed_requests.iter().all(|r| r.url.query_pairs().any(|(k, v)| k == "translatedLanguage[]" && v == "en")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saves_publication_details() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 1)];
+    mount_series(&mock, &chapters, &[]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        metadata_file_format: Some(MetadataOutputFormat::JSON),
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let metadata:serde_json::Value = serde_json::from_str(&fs::read_to_string(series_dir(output.path()).join("metadata.json")).unwrap()).unwrap();
+    assert_eq!(metadata["authors"], serde_json::json!(["Test Author"]));
+    assert_eq!(metadata["artists"], serde_json::json!(["Test Artist"]));
+    assert_eq!(metadata["status"], "ongoing");
+    assert_eq!(metadata["year"], 2014);
+    assert_eq!(metadata["demographic"], "seinen");
+    assert_eq!(metadata["original_language"], "ja");
+    assert_eq!(metadata["content_rating"], "safe");
+    assert!(metadata["last_volume"].is_null());
+    assert_eq!(metadata["links"], serde_json::json!({
+        "anilist": "https://anilist.co/manga/100",
+        "myanimelist": "https://myanimelist.net/manga/200",
+        "mangaupdates": "https://www.mangaupdates.com/series/pb8uwds",
+        "official_english": "https://example.com/test-manga",
+    }));
+
+    let manga_requests = mock.requests_to(&format!("/manga/{}", MANGA_ID)).await;
+    assert!(manga_requests.iter().all(|r| r.url.query_pairs().any(|(k, v)| k == "includes[]" && v == "author")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saves_tachiyomi_layout() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 2),
+        ChapterFixture::new(2, None, Some("3"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        layout: LayoutPreset::Tachiyomi,
+        cbz: true,
+        ..options(output.path())
+    };
+    let report = download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let series = series_dir(output.path());
+    assert_eq!(report.chapters[0].archive, Some(series.join("Vol.1 Ch.1.cbz")));
+    assert!(!series.join("Vol.1 Ch.1").exists());
+    assert!(series.join("Ch.3.cbz").is_file());
+    assert!(!series.join("Volume 1").exists());
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(series.join("Vol.1 Ch.1.cbz")).unwrap()).unwrap();
+    assert_eq!(archive.len(), 2);
+    let mut page = Vec::new();
+    std::io::Read::read_to_end(&mut archive.by_name("2.png").unwrap(), &mut page).unwrap();
+    assert_eq!(page, chapters[0].pages[1]);
+
+    // The series cover, not the volume one
+    assert!(series.join("cover.jpg").is_file());
+    assert_eq!(report.covers.len(), 1);
+    assert_eq!(mock.requests_to(&format!("/covers/{}/main.jpg", MANGA_ID)).await.len(), 1);
+
+    let details:serde_json::Value = serde_json::from_str(&fs::read_to_string(series.join("details.json")).unwrap()).unwrap();
+    assert_eq!(details["title"], TITLE);
+    assert_eq!(details["author"], "Test Author");
+    assert_eq!(details["artist"], "Test Artist");
+    assert_eq!(details["genre"], serde_json::json!(["Comedy"]));
+    assert_eq!(details["status"], "1");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saves_komga_layout() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("5"), 1),
+        ChapterFixture::new(2, Some("1"), Some("5.5"), 1),
+        ChapterFixture::new(3, None, Some("12"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        layout: LayoutPreset::Komga,
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    // Always archived
+    let series = series_dir(output.path());
+    assert!(series.join(format!("{} Vol.01 Ch.005.cbz", TITLE)).is_file());
+    assert!(series.join(format!("{} Vol.01 Ch.005.5.cbz", TITLE)).is_file());
+    assert!(series.join(format!("{} Ch.012.cbz", TITLE)).is_file());
+    assert!(series.join("cover.jpg").is_file());
+
+    let mylar:serde_json::Value = serde_json::from_str(&fs::read_to_string(series.join("series.json")).unwrap()).unwrap();
+    assert_eq!(mylar["metadata"]["type"], "comicSeries");
+    assert_eq!(mylar["metadata"]["name"], TITLE);
+    assert_eq!(mylar["metadata"]["year"], 2014);
+    assert_eq!(mylar["metadata"]["status"], "Continuing");
+    assert_eq!(mylar["metadata"]["age_rating"], "Everyone");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_files_from_templates() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 2),
+        ChapterFixture::new(2, None, Some("2"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        templates: Templates {
+            series: Some(Template::parse("{title} ({year})", TemplateKind::Series).unwrap()),
+            chapter: Some(Template::parse("Vol.{volume:02}/Ch.{chapter:04} [{group}]", TemplateKind::Chapter).unwrap()),
+            page: Some(Template::parse("{page:03}", TemplateKind::Page).unwrap()),
+            cover: Some(Template::parse("covers/{volume:02}.{ext}", TemplateKind::Cover).unwrap()),
+            metadata: Some(Template::parse("{id}.{ext}", TemplateKind::Metadata).unwrap()),
+        },
+        ..options(output.path())
+    };
+    let report = download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let series = output.path().join(format!("{} (2014)", TITLE));
+    assert_eq!(report.directory, series);
+    let group = &report.chapters[0].metadata.group;
+    assert_eq!(fs::read(series.join(format!("Vol.01/Ch.0001 [{}]/002.png", group))).unwrap(), chapters[0].pages[1]);
+    // No volume, so no volume folder
+    assert!(series.join(format!("Ch.0002 [{}]/001.png", group)).is_file());
+    assert!(series.join("covers/01.jpg").is_file());
+    assert_eq!(report.metadata_file, Some(series.join(format!("{}.toml", MANGA_ID))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pads_numbers_to_the_whole_series() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("5"), 1),
+        ChapterFixture::new(2, Some("1"), Some("10.5"), 1),
+        ChapterFixture::new(3, Some("12"), Some("120"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    // Padding doesn't depend on what's selected
+    let output = TempDir::new().unwrap();
+    let selected = DownloadOptions {
+        ranges: Some("1".parse().unwrap()),
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &selected).await.unwrap();
+
+    let series = series_dir(output.path());
+    assert!(series.join("Volume 01/Chapter 005/1.png").is_file());
+    assert!(series.join("Volume 01/Chapter 010.5/1.png").is_file());
+    assert!(series.join("Volume 01/cover.jpg").is_file());
+    assert!(!series.join("Volume 12").exists());
+
+    let output = TempDir::new().unwrap();
+    let unpadded = DownloadOptions {
+        padding: Padding::None,
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &unpadded).await.unwrap();
+    assert!(series_dir(output.path()).join("Volume 12/Chapter 120/1.png").is_file());
+    assert!(series_dir(output.path()).join("Volume 1/Chapter 5/1.png").is_file());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn converts_pages() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        // The second page can't be decoded, so is left alone
+        ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![mock::png_page(200, 400), mock::page_bytes(1, 1), mock::png_page(30, 60)]),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        processing: ProcessOptions {
+            format: Some(ConvertFormat::JPEG),
+            max_height: Some(100),
+            grayscale: true,
+            ..ProcessOptions::default()
+        },
+        workers: 2,
+        ..options(output.path())
+    };
+    let report = download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let chapter = series_dir(output.path()).join("Volume 1/Chapter 1");
+    assert_eq!(report.chapters[0].pages, vec![chapter.join("1.jpg"), chapter.join("2.png"), chapter.join("3.jpg")]);
+    assert_eq!(fs::read_dir(&chapter).unwrap().count(), 3);
+
+    let first = image::open(chapter.join("1.jpg")).unwrap();
+    assert_eq!((first.width(), first.height()), (50, 100));
+    assert_eq!(first.color(), image::ColorType::L8);
+    // Already short enough
+    assert_eq!(image::open(chapter.join("3.jpg")).unwrap().height(), 60);
+    assert_eq!(fs::read(chapter.join("2.png")).unwrap(), chapters[0].pages[1]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn splits_spreads() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![mock::png_page(200, 100), mock::png_page(50, 100)]),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let split = DownloadOptions {
+        processing: ProcessOptions {
+            spreads: Some(SpreadMode::Split),
+            ..ProcessOptions::default()
+        },
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &split).await.unwrap();
+
+    // Manga read right to left, so the right half comes first
+    let chapter = series_dir(output.path()).join("Volume 1/Chapter 1");
+    let first = image::open(chapter.join("1.png")).unwrap().to_rgb8();
+    let second = image::open(chapter.join("2.png")).unwrap().to_rgb8();
+    assert_eq!(first.dimensions(), (100, 100));
+    assert_eq!(first.get_pixel(0, 0)[0], 100);
+    assert_eq!(second.get_pixel(0, 0)[0], 0);
+    assert_eq!(image::open(chapter.join("3.png")).unwrap().width(), 50);
+    assert_eq!(fs::read_dir(&chapter).unwrap().count(), 3);
+
+    let output = TempDir::new().unwrap();
+    let rotate = DownloadOptions {
+        processing: ProcessOptions {
+            spreads: Some(SpreadMode::Rotate),
+            ..ProcessOptions::default()
+        },
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &rotate).await.unwrap();
+
+    // Rotated so the right edge is at the top
+    let rotated = image::open(series_dir(output.path()).join("Volume 1/Chapter 1/1.png")).unwrap().to_rgb8();
+    assert_eq!(rotated.dimensions(), (100, 200));
+    assert_eq!(rotated.get_pixel(0, 0)[0], 199);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crops_borders() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![
+            mock::bordered_png_page(100, 200, 10, 250),
+            mock::bordered_png_page(100, 200, 5, 0),
+            // Cropping would leave too little
+            mock::bordered_png_page(100, 200, 40, 255),
+        ]),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        processing: ProcessOptions {
+            crop: true,
+            ..ProcessOptions::default()
+        },
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let chapter = series_dir(output.path()).join("Volume 1/Chapter 1");
+    assert_eq!(image::open(chapter.join("1.png")).unwrap().to_rgb8().dimensions(), (80, 180));
+    assert_eq!(image::open(chapter.join("2.png")).unwrap().to_rgb8().dimensions(), (90, 190));
+    assert_eq!(image::open(chapter.join("3.png")).unwrap().to_rgb8().dimensions(), (100, 200));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stitches_long_strips() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![
+            mock::strip_png_page(50, 300, &[0..250, 260..300]),
+            mock::strip_png_page(50, 300, std::slice::from_ref(&(0..300))),
+            mock::strip_png_page(50, 300, &[0..20, 30..300]),
+        ]),
+    ];
+    mock.mount_manga_with_tags(&["Long Strip"]).await;
+    mock.mount_feed(&chapters).await;
+    mock.mount_covers(&["1"]).await;
+    mock.mount_chapter(&chapters[0]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        processing: ProcessOptions {
+            stitch: true,
+            slice_height: 400,
+            ..ProcessOptions::default()
+        },
+        ..options(output.path())
+    };
+    let report = download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    // Cut at the last gutter row within each slice, and wherever the strip runs out
+    let chapter = series_dir(output.path()).join("Volume 1/Chapter 1");
+    let heights = report.chapters[0].pages.iter()
+        .map(|p| image::open(p).unwrap().height())
+        .collect::<Vec<u32>>();
+    assert_eq!(heights, vec![259, 370, 271]);
+    assert_eq!(report.chapters[0].pages[0], chapter.join("1.png"));
+    assert_eq!(fs::read_dir(&chapter).unwrap().count(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stitches_only_long_strips() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 2)];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        processing: ProcessOptions {
+            stitch: true,
+            ..ProcessOptions::default()
+        },
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let chapter = series_dir(output.path()).join("Volume 1/Chapter 1");
+    assert_eq!(fs::read(chapter.join("2.png")).unwrap(), chapters[0].pages[1]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_pages_by_their_contents() {
+    let mock = MockMangaDex::start().await;
+    let mut jpeg = b"\xff\xd8\xff\xe0".to_vec();
+    jpeg.extend_from_slice(b"not really a png");
+    let chapter = ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![jpeg, b"neither".to_vec()]);
+    mock.mount_manga().await;
+    mock.mount_feed(std::slice::from_ref(&chapter)).await;
+    mock.mount_covers(&[]).await;
+    mock.mount_chapter_serving_as(&chapter, &chapter.pages, "image/png").await;
+
+    let output = TempDir::new().unwrap();
+    download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+
+    // The bytes win over the Content-Type, which is only used when they can't be recognised
+    let chapter_dir = series_dir(output.path()).join("Volume 1/Chapter 1");
+    assert!(chapter_dir.join("1.jpg").is_file());
+    assert!(chapter_dir.join("2.png").is_file());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_only_selected_range() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 1),
+        ChapterFixture::new(2, Some("2"), Some("2"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1", "2"]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        ranges: Some("2".parse().unwrap()),
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let series = series_dir(output.path());
+    assert!(!series.join("Volume 1").exists());
+    assert!(series.join("Volume 2/Chapter 2/1.png").is_file());
+    assert!(series.join("Volume 2/cover.jpg").is_file());
+    assert_eq!(mock.requests_to(&format!("/at-home/server/{}", chapters[0].id)).await.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resolving_does_not_download() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 1)];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let selection = resolve(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+
+    assert_eq!(selection.title, TITLE);
+    assert_eq!(selection.directory, series_dir(output.path()));
+    assert_eq!(selection.chapters.iter().map(|c| c.id.clone()).collect::<Vec<String>>(), vec![chapters[0].id.clone()]);
+    assert!(!series_dir(output.path()).exists());
+    assert_eq!(mock.requests_to(&format!("/at-home/server/{}", chapters[0].id)).await.len(), 0);
+    assert_eq!(mock.requests_to("/cover").await.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_lists_without_downloading() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 2),
+        ChapterFixture::new(2, None, Some("2"), 1),
+    ];
+    mount_series(&mock, &chapters, &["1"]).await;
+
+    let output = TempDir::new().unwrap();
+    let args = vec![
+        String::from("--dry-run"),
+        String::from("--api-url"), mock.uri(),
+        String::from("--uploads-url"), mock.uri(),
+        String::from("--output-dir"), output.path().display().to_string(),
+        mock::manga_url(),
+    ];
+    let result = tokio::task::spawn_blocking(move || std::process::Command::new(env!("CARGO_BIN_EXE_mangadex-downloader")).args(args).output())
+        .await.unwrap().unwrap();
+    assert!(result.status.success());
+
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let series = series_dir(output.path());
+    assert!(stdout.contains("Chapter title 1"));
+    assert!(stdout.contains(&series.join("Volume 1/Chapter 1").display().to_string()));
+    assert!(stdout.contains(&series.join("Chapter 2").display().to_string()));
+    assert!(stdout.contains("2 chapter(s)"));
+
+    assert_eq!(fs::read_dir(output.path()).unwrap().count(), 0);
+    for chapter in chapters.iter() {
+        assert_eq!(mock.requests_to(&format!("/at-home/server/{}", chapter.id)).await.len(), 0);
+    }
+    assert_eq!(mock.requests_to("/cover").await.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn skips_external_chapters() {
+    let mock = MockMangaDex::start().await;
+    let mut external = ChapterFixture::new(2, Some("1"), Some("2"), 0);
+    external.external_url = Some(String::from("https://example.com/official/2"));
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 1), external];
+    mount_series(&mock, &chapters, &[]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        external_shortcuts: true,
+        ..options(output.path())
+    };
+    let report = download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+    assert_eq!(report.skipped.len(), 1);
+
+    let series = series_dir(output.path());
+    assert!(series.join("Volume 1/Chapter 1/1.png").is_file());
+    assert!(!series.join("Volume 1/Chapter 2").exists());
+    assert!(fs::read_to_string(series.join("Volume 1/Chapter 2.url")).unwrap().contains("URL=https://example.com/official/2"));
+    assert_eq!(mock.requests_to(&format!("/at-home/server/{}", chapters[1].id)).await.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_corrupt_pages() {
+    let mock = MockMangaDex::start().await;
+    let chapter = ChapterFixture::new(1, Some("1"), Some("1"), 1);
+    mock.mount_manga().await;
+    mock.mount_feed(std::slice::from_ref(&chapter)).await;
+    mock.mount_covers(&[]).await;
+    mock.mount_chapter_serving(&chapter, &[b"not the page you're looking for".to_vec()]).await;
+
+    let output = TempDir::new().unwrap();
+    let res = download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await;
+
+    assert!(matches!(res, Err(DownloadError::API(APIError::Download(ImageDownloadError::HashMismatch)))));
+    // Neither the page nor the partial download is left behind
+    let chapter_dir = series_dir(output.path()).join("Volume 1/Chapter 1");
+    assert_eq!(fs::read_dir(&chapter_dir).unwrap().count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_large_pages() {
+    let mock = MockMangaDex::start().await;
+    let mut page = mock::page_bytes(1, 0);
+    page.extend((0..8 * 1024 * 1024).map(|i:u32| (i % 251) as u8));
+    let chapters = vec![ChapterFixture::with_pages(1, Some("1"), Some("1"), vec![page])];
+    mount_series(&mock, &chapters, &[]).await;
+
+    let output = TempDir::new().unwrap();
+    download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+
+    assert_eq!(fs::read(series_dir(output.path()).join("Volume 1/Chapter 1/1.png")).unwrap(), chapters[0].pages[0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clears_stale_partial_downloads() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 1)];
+    mount_series(&mock, &chapters, &[]).await;
+
+    // As left by an earlier run that was interrupted
+    let output = TempDir::new().unwrap();
+    let chapter_dir = series_dir(output.path()).join("Volume 1/Chapter 1");
+    fs::create_dir_all(&chapter_dir).unwrap();
+    fs::write(chapter_dir.join(".1-0000.png.part"), b"half a page").unwrap();
+
+    download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+
+    let names = fs::read_dir(&chapter_dir).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(names, ["1.png"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn skips_verification_when_asked() {
+    let mock = MockMangaDex::start().await;
+    let chapter = ChapterFixture::new(1, Some("1"), Some("1"), 1);
+    mock.mount_manga().await;
+    mock.mount_feed(std::slice::from_ref(&chapter)).await;
+    mock.mount_covers(&[]).await;
+    mock.mount_chapter_serving(&chapter, &[b"not the page you're looking for".to_vec()]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        verify: false,
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    assert_eq!(fs::read(series_dir(output.path()).join("Volume 1/Chapter 1/1.png")).unwrap(), b"not the page you're looking for");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verify_catches_pages_downloaded_without_verification() {
+    let mock = MockMangaDex::start().await;
+    let chapter = ChapterFixture::new(1, Some("1"), Some("1"), 2);
+    mock.mount_manga().await;
+    mock.mount_feed(std::slice::from_ref(&chapter)).await;
+    mock.mount_covers(&[]).await;
+    mock.mount_chapter_serving(&chapter, &[chapter.pages[0].clone(), b"corrupt in transit".to_vec()]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        verify: false,
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let report = verify(&mut api(&mock), &series_dir(output.path()), false).await.unwrap();
+    assert_eq!(report.checked, 2);
+    let found = report.problems.iter()
+        .map(|p| (report.describe(p), p.kind))
+        .collect::<Vec<(String, ProblemKind)>>();
+    assert_eq!(found, vec![(String::from("Volume 1/Chapter 1/2.png"), ProblemKind::Corrupt)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verifies_and_refetches_pages() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![
+        ChapterFixture::new(1, Some("1"), Some("1"), 2),
+        ChapterFixture::new(2, Some("1"), Some("2"), 1),
+    ];
+    mount_series(&mock, &chapters, &[]).await;
+
+    let output = TempDir::new().unwrap();
+    let report = download(&mut api(&mock), &mock::manga_url(), &options(output.path())).await.unwrap();
+    let series = series_dir(output.path());
+    assert_eq!(report.manifest_file, Some(series.join("manifest.json")));
+
+    let clean = verify(&mut api(&mock), &series, false).await.unwrap();
+    assert_eq!(clean.checked, 3);
+    assert!(clean.problems.is_empty());
+
+    fs::write(series.join("Volume 1/Chapter 1/2.png"), b"bit rot").unwrap();
+    fs::remove_file(series.join("Volume 1/Chapter 2/1.png")).unwrap();
+
+    let damaged = verify(&mut api(&mock), &series, false).await.unwrap();
+    let mut found = damaged.problems.iter()
+        .map(|p| (damaged.describe(p), p.kind))
+        .collect::<Vec<(String, ProblemKind)>>();
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(found, vec![
+        (String::from("Volume 1/Chapter 1/2.png"), ProblemKind::Corrupt),
+        (String::from("Volume 1/Chapter 2/1.png"), ProblemKind::Missing),
+    ]);
+
+    let fixed = verify(&mut api(&mock), &series, true).await.unwrap();
+    assert!(fixed.problems.iter().all(|p| p.refetched));
+    assert_eq!(fs::read(series.join("Volume 1/Chapter 1/2.png")).unwrap(), chapters[0].pages[1]);
+    assert_eq!(fs::read(series.join("Volume 1/Chapter 2/1.png")).unwrap(), chapters[1].pages[0]);
+    assert!(verify(&mut api(&mock), &series, false).await.unwrap().problems.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refetches_pages_into_archives() {
+    let mock = MockMangaDex::start().await;
+    let chapters = vec![ChapterFixture::new(1, Some("1"), Some("1"), 2)];
+    mount_series(&mock, &chapters, &[]).await;
+
+    let output = TempDir::new().unwrap();
+    let options = DownloadOptions {
+        cbz: true,
+        ..options(output.path())
+    };
+    download(&mut api(&mock), &mock::manga_url(), &options).await.unwrap();
+
+    let series = series_dir(output.path());
+    let archive = series.join("Volume 1/Chapter 1.cbz");
+    fs::remove_file(&archive).unwrap();
+
+    let report = verify(&mut api(&mock), &series, true).await.unwrap();
+    assert_eq!(report.problems.len(), 2);
+    assert!(report.problems.iter().all(|p| p.kind == ProblemKind::Missing && p.refetched));
+    assert!(archive.is_file());
+    assert!(verify(&mut api(&mock), &series, false).await.unwrap().problems.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retries_when_rate_limited() {
+    let mock = MockMangaDex::start().await;
+    mock.mount_manga().await;
+    mock.rate_limit_once(&format!("/manga/{}", MANGA_ID)).await;
+
+    let mut api = api(&mock);
+    let manga = api.get_manga_metadata(&mock::manga_url()).await.unwrap();
+
+    assert_eq!(manga.get_title("en").unwrap(), TITLE);
+    assert_eq!(mock.requests_to(&format!("/manga/{}", MANGA_ID)).await.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pages_through_feed() {
+    let mock = MockMangaDex::start().await;
+    mock.mount_manga().await;
+    let chapters = (0..1200)
+        .map(|n| ChapterFixture::new(n, Some("1"), Some(&n.to_string()), 1))
+        .collect::<Vec<ChapterFixture>>();
+    mock.mount_feed(&chapters).await;
+
+    let mut api = api(&mock);
+    let manga = api.get_manga_metadata(&mock::manga_url()).await.unwrap();
+    let metadata = api.get_chapter_metadata(&manga, &feed_filter(), true).await.unwrap();
+
+    assert_eq!(metadata.get_download_metadata("en", &None, &None, TLSelectionStrategy::Frequency).len(), 1200);
+    assert_eq!(mock.requests_to(&format!("/manga/{}/feed", MANGA_ID)).await.len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn encodes_feed_filters() {
+    let mock = MockMangaDex::start().await;
+    mock.mount_manga().await;
+    mock.mount_feed(&[ChapterFixture::new(1, Some("1"), Some("1"), 1)]).await;
+
+    let filter = FeedFilter {
+        excluded_groups: vec![String::from("a&b=c#d e")],
+        ..feed_filter()
+    };
+    let mut api = api(&mock);
+    let manga = api.get_manga_metadata(&mock::manga_url()).await.unwrap();
+    api.get_chapter_metadata(&manga, &filter, true).await.unwrap();
+
+    let feed_requests = mock.requests_to(&format!("/manga/{}/feed", MANGA_ID)).await;
+    let query = feed_requests[0].url.query_pairs().into_owned().collect::<Vec<(String, String)>>();
+    assert!(query.contains(&(String::from("excludedGroups[]"), String::from("a&b=c#d e"))));
+    assert!(query.contains(&(String::from("translatedLanguage[]"), String::from("en"))));
+    assert!(!query.iter().any(|(k, _)| k == "b"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pages_through_feed_past_offset_limit() {
+    let mock = MockMangaDex::start().await;
+    mock.mount_manga().await;
+    let chapters = (0..10600)
+        .map(|n| ChapterFixture::new(n, None, Some(&n.to_string()), 1))
+        .collect::<Vec<ChapterFixture>>();
+    mock.mount_feed(&chapters).await;
+
+    let mut api = api(&mock);
+    let manga = api.get_manga_metadata(&mock::manga_url()).await.unwrap();
+    let metadata = api.get_chapter_metadata(&manga, &feed_filter(), true).await.unwrap();
+
+    assert_eq!(metadata.get_download_metadata("en", &None, &None, TLSelectionStrategy::Frequency).len(), 10600);
+
+    let feed_requests = mock.requests_to(&format!("/manga/{}/feed", MANGA_ID)).await;
+    assert!(feed_requests.iter().any(|r| r.url.query_pairs().any(|(k, _)| k == "createdAtSince")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pages_through_bulk_uploads_past_offset_limit() {
+    let mock = MockMangaDex::start().await;
+    mock.mount_manga().await;
+    let chapters = (0..10600)
+        .map(|n| ChapterFixture::new(n, None, Some(&n.to_string()), 1))
+        .collect::<Vec<ChapterFixture>>();
+    // Uploaded 2000 at a time, so that the first window ends partway through a second
+    mock.mount_feed_created(&chapters, |n| Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, (n / 2000) as u32).unwrap()).await;
+
+    let mut api = api(&mock);
+    let manga = api.get_manga_metadata(&mock::manga_url()).await.unwrap();
+    let metadata = api.get_chapter_metadata(&manga, &feed_filter(), true).await.unwrap();
+
+    assert_eq!(metadata.get_download_metadata("en", &None, &None, TLSelectionStrategy::Frequency).len(), 10600);
+
+    // The second window skips the 2000 chapters from that second that were already seen, rather than fetching them again
+    let feed_requests = mock.requests_to(&format!("/manga/{}/feed", MANGA_ID)).await;
+    assert_eq!(feed_requests.len(), 22);
+    let resumed = feed_requests.iter()
+        .find(|r| r.url.query_pairs().any(|(k, _)| k == "createdAtSince"))
+        .unwrap();
+    assert!(resumed.url.query_pairs().any(|(k, v)| k == "createdAtSince" && v == "2020-01-01T00:00:04"));
+    assert!(resumed.url.query_pairs().any(|(k, v)| k == "offset" && v == "2000"));
+}
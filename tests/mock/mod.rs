@@ -0,0 +1,297 @@
+// A stand-in for the parts of the MangaDex API the downloader uses, so that it can be tested without the network
+
+use std::collections::HashMap;
+
+use chrono::{ DateTime, Duration, TimeZone, Utc };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+use wiremock::{ Mock, MockServer, Request, Respond, ResponseTemplate };
+use wiremock::matchers::{ method, path, path_regex, query_param };
+
+pub const MANGA_ID:&str = "348966d0-c807-45cf-9260-8adf006a9da6";
+pub const TITLE:&str = "Test Manga";
+
+pub fn manga_url() -> String {
+    format!("https://mangadex.org/title/{}/test-manga", MANGA_ID)
+}
+
+#[derive(Debug, Clone)]
+pub struct ChapterFixture {
+    pub id: String,
+    pub volume: Option<String>,
+    pub chapter: Option<String>,
+    pub group: String,
+    pub external_url: Option<String>,
+    pub pages: Vec<Vec<u8>>,
+}
+impl ChapterFixture {
+    pub fn new(n:usize, volume:Option<&str>, chapter:Option<&str>, pages:usize) -> Self {
+        Self {
+            id: format!("00000000-0000-0000-0000-{:012}", n),
+            volume: volume.map(|v| v.to_string()),
+            chapter: chapter.map(|c| c.to_string()),
+            group: String::from("Test Scans"),
+            external_url: None,
+            pages: (0..pages).map(|p| page_bytes(n, p)).collect(),
+        }
+    }
+
+    pub fn with_pages(n:usize, volume:Option<&str>, chapter:Option<&str>, pages:Vec<Vec<u8>>) -> Self {
+        Self {
+            pages,
+            ..Self::new(n, volume, chapter, 0)
+        }
+    }
+
+    fn to_json(&self, n:usize, created_at:DateTime<Utc>) -> Value {
+        json!({
+            "id": self.id,
+            "type": "chapter",
+            "attributes": {
+                "volume": self.volume,
+                "chapter": self.chapter,
+                "title": format!("Chapter title {}", n),
+                "translatedLanguage": "en",
+                "externalUrl": self.external_url,
+                "publishAt": created_at.to_rfc3339(),
+                "readableAt": created_at.to_rfc3339(),
+                "createdAt": created_at.to_rfc3339(),
+                "pages": self.pages.len(),
+                "version": 1,
+            },
+            "relationships": [
+                { "id": "11111111-1111-1111-1111-111111111111", "type": "scanlation_group", "attributes": { "name": self.group } },
+                { "id": "22222222-2222-2222-2222-222222222222", "type": "user" },
+            ],
+        })
+    }
+
+    // Page filenames embed the SHA-256 of the page, as MangaDex's do
+    pub fn filenames(&self) -> Vec<String> {
+        self.pages.iter().enumerate()
+            .map(|(i, page)| format!("{}-{}.png", i + 1, hex::encode(Sha256::digest(page))))
+            .collect()
+    }
+}
+
+pub fn page_bytes(chapter:usize, page:usize) -> Vec<u8> {
+    let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+    bytes.extend_from_slice(format!("chapter {} page {}", chapter, page).as_bytes());
+    bytes
+}
+
+// A real, decodable page, with a colour gradient so that it isn't already gray
+pub fn png_page(width:u32, height:u32) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]));
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+// A page with a gradient in the middle, and a border of the given shade this many pixels wide
+pub fn bordered_png_page(width:u32, height:u32, border:u32, shade:u8) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| match x < border || y < border || x >= width - border || y >= height - border {
+        true => image::Rgb([shade, shade, shade]),
+        false => image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]),
+    });
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+// A long strip page: white gutters, with art in the given rows
+pub fn strip_png_page(width:u32, height:u32, art:&[std::ops::Range<u32>]) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| match art.iter().any(|a| a.contains(&y)) {
+        true => image::Rgb([(x % 2 * 255) as u8, 100, 100]),
+        false => image::Rgb([255, 255, 255]),
+    });
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+// Pages through the feed the way the real API does, including its refusal to go past offset + limit > 10000
+struct FeedResponder {
+    chapters: Vec<Value>,
+}
+impl Respond for FeedResponder {
+    fn respond(&self, request:&Request) -> ResponseTemplate {
+        let query = request.url.query_pairs().into_owned().collect::<HashMap<String, String>>();
+        let limit = query.get("limit").and_then(|l| l.parse::<usize>().ok()).unwrap_or(100);
+        let offset = query.get("offset").and_then(|o| o.parse::<usize>().ok()).unwrap_or(0);
+
+        if limit > 500 || offset + limit > 10000 {
+            return ResponseTemplate::new(400).set_body_json(json!({ "result": "error", "errors": [{ "detail": "offset out of range" }] }));
+        }
+
+        let matching = self.chapters.iter()
+            .filter(|c| match query.get("createdAtSince") {
+                Some(since) => c["attributes"]["createdAt"].as_str().unwrap()[..19] >= since[..],
+                None => true,
+            })
+            .collect::<Vec<&Value>>();
+
+        let data = matching.iter().skip(offset).take(limit).collect::<Vec<&&Value>>();
+        ResponseTemplate::new(200).set_body_json(json!({
+            "result": "ok",
+            "data": data,
+            "limit": limit,
+            "offset": offset,
+            "total": matching.len(),
+        }))
+    }
+}
+
+pub struct MockMangaDex {
+    pub server: MockServer,
+}
+impl MockMangaDex {
+    pub async fn start() -> Self {
+        Self {
+            server: MockServer::start().await,
+        }
+    }
+
+    pub fn uri(&self) -> String {
+        self.server.uri()
+    }
+
+    pub async fn mount_manga(&self) {
+        self.mount_manga_with_tags(&["Comedy"]).await;
+    }
+
+    pub async fn mount_manga_with_tags(&self, tags:&[&str]) {
+        let tags = tags.iter()
+            .map(|t| json!({ "attributes": { "name": { "en": t } } }))
+            .collect::<Vec<Value>>();
+
+        Mock::given(method("GET"))
+            .and(path(format!("/manga/{}", MANGA_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "result": "ok",
+                "data": {
+                    "id": MANGA_ID,
+                    "type": "manga",
+                    "attributes": {
+                        "title": { "en": TITLE },
+                        "altTitles": [{ "ja": "テスト漫画" }, { "ja-ro": "Tesuto Manga" }],
+                        "availableTranslatedLanguages": ["en"],
+                        "description": { "en": "A manga that only exists in tests." },
+                        "tags": tags,
+                        "links": { "al": "100", "mal": "200", "mu": "pb8uwds", "engtl": "https://example.com/test-manga", "xyz": "dropped" },
+                        "originalLanguage": "ja",
+                        "lastVolume": "",
+                        "lastChapter": "",
+                        "publicationDemographic": "seinen",
+                        "status": "ongoing",
+                        "year": 2014,
+                        "contentRating": "safe",
+                    },
+                    "relationships": [
+                        { "id": "author-1", "type": "author", "attributes": { "name": "Test Author" } },
+                        { "id": "artist-1", "type": "artist", "attributes": { "name": "Test Artist" } },
+                        { "id": "cover-main", "type": "cover_art", "attributes": { "volume": "1", "fileName": "main.jpg" } },
+                    ],
+                },
+            })))
+            .mount(&self.server)
+            .await;
+    }
+
+    // Creation dates are one minute apart, in order
+    pub async fn mount_feed(&self, chapters:&[ChapterFixture]) {
+        self.mount_feed_created(chapters, |n| Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(n as i64)).await;
+    }
+
+    // Creation dates are given by the index of each chapter, e.g. to have a bulk upload share one
+    pub async fn mount_feed_created(&self, chapters:&[ChapterFixture], created_at:impl Fn(usize) -> DateTime<Utc>) {
+        Mock::given(method("GET"))
+            .and(path(format!("/manga/{}/feed", MANGA_ID)))
+            .respond_with(FeedResponder {
+                chapters: chapters.iter().enumerate().map(|(n, c)| c.to_json(n, created_at(n))).collect(),
+            })
+            .mount(&self.server)
+            .await;
+    }
+
+    // Covers are named after their volume
+    pub async fn mount_covers(&self, volumes:&[&str]) {
+        let data = volumes.iter()
+            .map(|v| json!({ "id": format!("cover-{}", v), "type": "cover_art", "attributes": { "volume": v, "fileName": format!("{}.jpg", v) } }))
+            .collect::<Vec<Value>>();
+
+        Mock::given(method("GET"))
+            .and(path("/cover"))
+            .and(query_param("manga[]", MANGA_ID))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "result": "ok",
+                "data": data,
+                "limit": 10,
+                "offset": 0,
+                "total": volumes.len(),
+            })))
+            .mount(&self.server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path_regex(format!(r"^/covers/{}/.+\.jpg$", MANGA_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"\xff\xd8\xff\xe0cover".to_vec(), "image/jpeg"))
+            .mount(&self.server)
+            .await;
+    }
+
+    pub async fn mount_chapter(&self, chapter:&ChapterFixture) {
+        self.mount_chapter_serving(chapter, &chapter.pages).await;
+    }
+
+    // Serves the given bytes in place of the chapter's pages, which allows corrupt pages to be simulated
+    pub async fn mount_chapter_serving(&self, chapter:&ChapterFixture, pages:&[Vec<u8>]) {
+        self.mount_chapter_serving_as(chapter, pages, "image/png").await;
+    }
+
+    pub async fn mount_chapter_serving_as(&self, chapter:&ChapterFixture, pages:&[Vec<u8>], content_type:&str) {
+        let hash = format!("hash{}", chapter.id.replace('-', ""));
+        let filenames = chapter.filenames();
+
+        Mock::given(method("GET"))
+            .and(path(format!("/at-home/server/{}", chapter.id)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "result": "ok",
+                "baseUrl": self.uri(),
+                "chapter": {
+                    "hash": hash,
+                    "data": filenames,
+                    "dataSaver": [],
+                },
+            })))
+            .mount(&self.server)
+            .await;
+
+        for (filename, page) in filenames.iter().zip(pages.iter()) {
+            Mock::given(method("GET"))
+                .and(path(format!("/data/{}/{}", hash, filename)))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(page.clone(), content_type))
+                .mount(&self.server)
+                .await;
+        }
+    }
+
+    // The next request to the path is rate limited, with a retry time of right now
+    pub async fn rate_limit_once(&self, p:&str) {
+        Mock::given(method("GET"))
+            .and(path(p))
+            .respond_with(ResponseTemplate::new(429).insert_header("X-RateLimit-Retry-After", Utc::now().timestamp().to_string().as_str()))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    pub async fn requests_to(&self, p:&str) -> Vec<Request> {
+        self.server.received_requests().await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|r| r.url.path() == p)
+            .collect()
+    }
+}